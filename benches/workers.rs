@@ -0,0 +1,185 @@
+//! Compares the work-stealing scheduler against the design it replaced,
+//! where every worker pulled from one `Arc<Mutex<Receiver<Task>>>`.
+//! Run it with `cargo bench`; set `VANESSA_BENCH_JOBS` to pick the number
+//! of workers instead of using one per CPU.
+
+#![allow(clippy::needless_return)]
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use vanessa::worker::WorkerPool;
+
+type Task = Option<Box<dyn FnOnce() + Send + 'static>>;
+
+/// A copy of the old worker pool: one channel, one receiver behind a mutex
+/// shared by every worker, and a completion channel per task.
+struct SharedReceiverPool {
+    sender: Sender<Task>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl SharedReceiverPool {
+    fn new(jobs: usize) -> SharedReceiverPool {
+        let (sender, receiver) = channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..jobs)
+            .map(|_| {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || loop {
+                    let task = receiver.lock().unwrap().recv().unwrap();
+                    match task {
+                        Some(task) => task(),
+                        None => break,
+                    }
+                })
+            })
+            .collect();
+        return SharedReceiverPool { sender, threads };
+    }
+
+    fn bg<F: FnOnce() + Send + 'static>(&self, f: F) -> Receiver<()> {
+        let (send, recv) = channel();
+        self.sender
+            .send(Some(Box::new(move || {
+                f();
+                send.send(()).ok();
+            })))
+            .unwrap();
+        return recv;
+    }
+
+    fn shutdown(self) {
+        for _ in &self.threads {
+            self.sender.send(None).unwrap();
+        }
+        for thread in self.threads {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Burns a bit of CPU so tasks have something to do.
+fn work(iterations: u64) -> u64 {
+    let mut x = 0u64;
+    for i in 0..iterations {
+        x = x.wrapping_mul(6364136223846793005).wrapping_add(i);
+    }
+    return std::hint::black_box(x);
+}
+
+fn report(name: &str, design: &str, tasks: usize, elapsed: Duration) {
+    println!(
+        "{name:<14} {design:<16} {tasks:>8} tasks in {:>10.2?} ({:>12.0} tasks/s)",
+        elapsed,
+        tasks as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn flat(name: &str, jobs: usize, tasks: usize, iterations: u64) {
+    let old = SharedReceiverPool::new(jobs);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..tasks)
+        .map(|_| {
+            old.bg(move || {
+                work(iterations);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.recv().unwrap();
+    }
+    report(name, "shared receiver", tasks, start.elapsed());
+    old.shutdown();
+
+    let new = WorkerPool::new("bench", jobs);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..tasks)
+        .map(|_| new.bg(move || work(iterations)).unwrap())
+        .collect();
+    for handle in handles {
+        handle.require().unwrap();
+    }
+    report(name, "work stealing", tasks, start.elapsed());
+    new.shutdown_blocking();
+}
+
+/// Tasks that spawn more tasks, which is where per-worker deques shine.
+fn nested(name: &str, jobs: usize, parents: usize, children: usize) {
+    let tasks = parents * (children + 1);
+
+    let old = Arc::new(SharedReceiverPool::new(jobs));
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..parents {
+        let pool = old.clone();
+        let done = done.clone();
+        old.bg(move || {
+            for _ in 0..children {
+                let done = done.clone();
+                pool.bg(move || {
+                    work(10);
+                    done.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            done.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    while done.load(Ordering::Relaxed) < tasks {
+        std::thread::yield_now();
+    }
+    report(name, "shared receiver", tasks, start.elapsed());
+    match Arc::try_unwrap(old) {
+        Ok(old) => old.shutdown(),
+        Err(_) => unreachable!(),
+    }
+
+    let new = Arc::new(WorkerPool::new("bench", jobs));
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..parents {
+        let pool = new.clone();
+        let done = done.clone();
+        new.bg(move || {
+            for _ in 0..children {
+                let done = done.clone();
+                pool.bg(move || {
+                    work(10);
+                    done.fetch_add(1, Ordering::Relaxed);
+                })
+                .unwrap();
+            }
+            done.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+    }
+    while done.load(Ordering::Relaxed) < tasks {
+        std::thread::yield_now();
+    }
+    report(name, "work stealing", tasks, start.elapsed());
+    new.shutdown_blocking();
+}
+
+fn main() {
+    let jobs = match std::env::var("VANESSA_BENCH_JOBS")
+        .ok()
+        .and_then(|j| j.parse().ok())
+    {
+        Some(jobs) => jobs,
+        None => match std::thread::available_parallelism() {
+            Ok(jobs) => jobs.get(),
+            Err(_) => 1,
+        },
+    };
+    println!("Running with {jobs} workers.");
+    flat("small tasks", jobs, 200_000, 10);
+    flat("large tasks", jobs, 2_000, 200_000);
+    nested("nested tasks", jobs, 1_000, 200);
+}
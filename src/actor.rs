@@ -0,0 +1,198 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    channel::{self, Receiver, Sender},
+    log::VANESSA_LOGGER,
+    panic_message, serror,
+    worker::{self, promise, Completer, Promise, Spawner, TaskError, WorkerPool},
+};
+
+/// The most messages an actor handles in one go before it gives other tasks
+/// on the pool a turn.
+const BATCH: usize = 32;
+
+/// Something that owns some state and changes it in response to messages.
+/// An actor only ever handles one message at a time, so it doesn't need any
+/// locking of its own.
+pub trait Actor: Send + 'static {
+    /// What the actor gets sent.
+    type Message: Send + 'static;
+    /// What the actor answers with.
+    type Reply: Send + 'static;
+
+    /// Handles a single message. What this returns is handed back to whoever
+    /// sent the message with `ActorRef::ask`.
+    fn handle(&mut self, message: Self::Message) -> Self::Reply;
+}
+
+struct Envelope<A: Actor> {
+    message: A::Message,
+    reply: Option<Completer<A::Reply>>,
+}
+
+struct Mailbox<A: Actor> {
+    actor: Mutex<A>,
+    send: Sender<Envelope<A>>,
+    recv: Receiver<Envelope<A>>,
+    /// Set while a task to handle messages is queued or running. There is
+    /// never more than one, which is what keeps the actor from running
+    /// concurrently with itself.
+    scheduled: AtomicBool,
+    stopped: AtomicBool,
+    spawner: Spawner,
+}
+
+/// A handle to a running actor. Cloning it gives another handle to the same
+/// actor.
+pub struct ActorRef<A: Actor> {
+    mailbox: Arc<Mailbox<A>>,
+}
+
+impl<A: Actor> Clone for ActorRef<A> {
+    fn clone(&self) -> Self {
+        return ActorRef {
+            mailbox: self.mailbox.clone(),
+        };
+    }
+}
+
+/// Starts `actor` on the global pool.
+pub fn spawn<A: Actor>(actor: A) -> Result<ActorRef<A>, TaskError> {
+    return spawn_on(worker::global(), actor);
+}
+
+/// Starts `actor` on `pool`. It doesn't take up a thread while it has
+/// nothing to do; whenever messages come in, a task to handle them is
+/// submitted to the pool. The actor stops working once the pool is shut
+/// down.
+pub fn spawn_on<A: Actor>(pool: &WorkerPool, actor: A) -> Result<ActorRef<A>, TaskError> {
+    let (send, recv) = channel::unbounded();
+    let mailbox = Mailbox {
+        actor: Mutex::new(actor),
+        send,
+        recv,
+        scheduled: AtomicBool::new(false),
+        stopped: AtomicBool::new(false),
+        spawner: pool.spawner()?,
+    };
+    return Ok(ActorRef {
+        mailbox: Arc::new(mailbox),
+    });
+}
+
+impl<A: Actor> ActorRef<A> {
+    /// Sends `message` to the actor without waiting for a reply. Fails if the
+    /// actor was stopped or its pool can't take the task to handle it.
+    pub fn send(&self, message: A::Message) -> Result<(), TaskError> {
+        return self.deliver(Envelope {
+            message,
+            reply: None,
+        });
+    }
+
+    /// Sends `message` to the actor and returns a promise of its reply. If
+    /// the actor panics while handling it, the promise gets
+    /// `TaskError::PANICKED`. Cancelling the promise before the actor gets
+    /// to the message makes it skip the message.
+    pub fn ask(&self, message: A::Message) -> Result<Promise<A::Reply>, TaskError> {
+        let (completer, reply) = promise();
+        self.deliver(Envelope {
+            message,
+            reply: Some(completer),
+        })?;
+        return Ok(reply);
+    }
+
+    /// Stops the actor from taking any more messages. Whatever was sent
+    /// before this is still handled.
+    pub fn stop(&self) {
+        self.mailbox.stopped.store(true, Ordering::Release);
+    }
+
+    /// Returns whether the actor has been stopped.
+    pub fn is_stopped(&self) -> bool {
+        return self.mailbox.stopped.load(Ordering::Acquire);
+    }
+
+    fn deliver(&self, envelope: Envelope<A>) -> Result<(), TaskError> {
+        if self.is_stopped() {
+            return Err(TaskError::SHUTDOWN);
+        }
+        if self.mailbox.send.send(envelope).is_err() {
+            return Err(TaskError::BROKENCHANNEL);
+        }
+        return schedule(&self.mailbox);
+    }
+}
+
+/// Makes sure there's a task on the pool to handle the actor's messages.
+/// If one can't be submitted, every message waiting in the mailbox is thrown
+/// away and the error is returned.
+fn schedule<A: Actor>(mailbox: &Arc<Mailbox<A>>) -> Result<(), TaskError> {
+    if mailbox.scheduled.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let m = mailbox.clone();
+    if let Err(e) = mailbox.spawner.bg(move || run(m)) {
+        serror!(
+            VANESSA_LOGGER,
+            "Failed to schedule actor {}: {e:?}",
+            std::any::type_name::<A>()
+        );
+        // nobody is going to handle these anymore
+        for envelope in mailbox.recv.try_iter() {
+            if let Some(reply) = envelope.reply {
+                reply.fail(e.clone());
+            }
+        }
+        mailbox.scheduled.store(false, Ordering::SeqCst);
+        return Err(e);
+    }
+    return Ok(());
+}
+
+/// Handles a batch of messages, then gets out of the way.
+fn run<A: Actor>(mailbox: Arc<Mailbox<A>>) {
+    let mut actor = mailbox.actor.lock().unwrap_or_else(|e| e.into_inner());
+    for envelope in mailbox.recv.try_iter().take(BATCH) {
+        if let Some(reply) = &envelope.reply {
+            if reply.is_cancelled() {
+                envelope.reply.unwrap().fail(TaskError::CANCELLED);
+                continue;
+            }
+        }
+        let message = envelope.message;
+        match catch_unwind(AssertUnwindSafe(|| actor.handle(message))) {
+            Ok(value) => {
+                if let Some(reply) = envelope.reply {
+                    reply.complete(value);
+                }
+            }
+            Err(payload) => {
+                let message = panic_message(&payload);
+                serror!(
+                    VANESSA_LOGGER,
+                    "Actor {} panicked while handling a message: {message}",
+                    std::any::type_name::<A>()
+                );
+                if let Some(reply) = envelope.reply {
+                    reply.fail(TaskError::PANICKED(message));
+                }
+            }
+        }
+    }
+    drop(actor);
+
+    mailbox.scheduled.store(false, Ordering::SeqCst);
+    // something might have come in after we stopped looking. if it can't be
+    // scheduled that has already been logged.
+    if !mailbox.recv.is_empty() {
+        schedule(&mailbox).ok();
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Why a value couldn't be sent. The value is handed back so it isn't lost.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The channel is full. Only happens with `try_send`.
+    FULL(T),
+    /// Every receiver is gone, nobody would ever get the value.
+    DISCONNECTED(T),
+    /// The channel stayed full for too long. Only happens with
+    /// `send_timeout`.
+    TIMEDOUT(T),
+}
+
+impl<T> SendError<T> {
+    /// Returns the value that couldn't be sent.
+    pub fn into_inner(self) -> T {
+        return match self {
+            SendError::FULL(value) => value,
+            SendError::DISCONNECTED(value) => value,
+            SendError::TIMEDOUT(value) => value,
+        };
+    }
+}
+
+/// Why nothing could be received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecvError {
+    /// The channel is empty. Only happens with `try_recv`.
+    EMPTY,
+    /// The channel is empty and every sender is gone, so it'll stay that
+    /// way.
+    DISCONNECTED,
+    /// The channel stayed empty for too long. Only happens with
+    /// `recv_timeout`.
+    TIMEDOUT,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receivers: usize,
+    /// How many receivers are blocked waiting for a value, and how many
+    /// senders are blocked waiting for room. Nobody has to be woken up while
+    /// these are zero, which saves a syscall on every send and receive.
+    waiting_receivers: usize,
+    waiting_senders: usize,
+    /// Whoever is waiting in `select` on this channel.
+    selectors: Vec<Arc<Signal>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Signalled when a value is sent or the last sender goes away.
+    not_empty: Condvar,
+    /// Signalled when a value is received or the last receiver goes away.
+    not_full: Condvar,
+    capacity: Option<usize>,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        // nothing in here can panic while holding the lock
+        return self.state.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    /// Lets receivers (and selectors) know something changed.
+    fn wake_receivers(&self, state: &State<T>, all: bool) {
+        if all {
+            self.not_empty.notify_all();
+        } else if state.waiting_receivers > 0 {
+            self.not_empty.notify_one();
+        }
+        for selector in &state.selectors {
+            selector.notify();
+        }
+    }
+}
+
+/// Wakes up a thread waiting in `select`.
+struct Signal {
+    ready: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Signal {
+    fn notify(&self) {
+        *self.ready.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        self.cond.notify_one();
+    }
+}
+
+/// The sending half of a channel. It can be cloned to get more senders, and
+/// shared between threads.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving half of a channel. Unlike `std::sync::mpsc`, this can be
+/// cloned too, and every value is received by exactly one of the receivers.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+fn new_channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            senders: 1,
+            receivers: 1,
+            waiting_receivers: 0,
+            waiting_senders: 0,
+            selectors: vec![],
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity,
+    });
+    return (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    );
+}
+
+/// Creates a channel that can hold any number of values. Sending never
+/// blocks.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    return new_channel(None);
+}
+
+/// Creates a channel that holds at most `capacity` values. Sending blocks
+/// while it's full. A capacity of 0 is treated as 1.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    return new_channel(Some(capacity.max(1)));
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for room if the channel is full. Fails if every
+    /// receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        return self.send_until(value, None, true);
+    }
+
+    /// Sends `value` if there's room right now.
+    pub fn try_send(&self, value: T) -> Result<(), SendError<T>> {
+        return self.send_until(value, None, false);
+    }
+
+    /// Sends `value`, waiting at most `timeout` for room.
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendError<T>> {
+        return self.send_until(value, Some(Instant::now() + timeout), true);
+    }
+
+    fn send_until(
+        &self,
+        value: T,
+        deadline: Option<Instant>,
+        block: bool,
+    ) -> Result<(), SendError<T>> {
+        let mut state = self.shared.lock();
+        loop {
+            if state.receivers == 0 {
+                return Err(SendError::DISCONNECTED(value));
+            }
+            let full = self
+                .shared
+                .capacity
+                .is_some_and(|capacity| state.queue.len() >= capacity);
+            if !full {
+                break;
+            }
+            if !block {
+                return Err(SendError::FULL(value));
+            }
+            state.waiting_senders += 1;
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        state.waiting_senders -= 1;
+                        return Err(SendError::TIMEDOUT(value));
+                    }
+                    match self.shared.not_full.wait_timeout(state, deadline - now) {
+                        Ok((state, _)) => state,
+                        Err(e) => e.into_inner().0,
+                    }
+                }
+                None => self
+                    .shared
+                    .not_full
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+            state.waiting_senders -= 1;
+        }
+        state.queue.push_back(value);
+        self.shared.wake_receivers(&state, false);
+        return Ok(());
+    }
+
+    /// Returns whether every receiver is gone.
+    pub fn is_disconnected(&self) -> bool {
+        return self.shared.lock().receivers == 0;
+    }
+
+    /// Returns how many values are waiting in the channel.
+    pub fn len(&self) -> usize {
+        return self.shared.lock().queue.len();
+    }
+
+    /// Returns whether there are no values waiting in the channel.
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Returns how many values the channel can hold, or `None` if it's
+    /// unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        return self.shared.capacity;
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for a value. Fails once the channel is empty and every sender
+    /// is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        return self.recv_until(None, true);
+    }
+
+    /// Takes a value if there is one right now.
+    pub fn try_recv(&self) -> Result<T, RecvError> {
+        return self.recv_until(None, false);
+    }
+
+    /// Waits at most `timeout` for a value.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvError> {
+        return self.recv_until(Some(Instant::now() + timeout), true);
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>, block: bool) -> Result<T, RecvError> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(value) = state.queue.pop_front() {
+                if state.waiting_senders > 0 {
+                    self.shared.not_full.notify_one();
+                }
+                return Ok(value);
+            }
+            if state.senders == 0 {
+                return Err(RecvError::DISCONNECTED);
+            }
+            if !block {
+                return Err(RecvError::EMPTY);
+            }
+            state.waiting_receivers += 1;
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        state.waiting_receivers -= 1;
+                        return Err(RecvError::TIMEDOUT);
+                    }
+                    match self.shared.not_empty.wait_timeout(state, deadline - now) {
+                        Ok((state, _)) => state,
+                        Err(e) => e.into_inner().0,
+                    }
+                }
+                None => self
+                    .shared
+                    .not_empty
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+            state.waiting_receivers -= 1;
+        }
+    }
+
+    /// Returns an iterator that waits for values until every sender is gone.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        return std::iter::from_fn(|| self.recv().ok());
+    }
+
+    /// Returns an iterator over the values that are in the channel right
+    /// now, without waiting for more.
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        return std::iter::from_fn(|| self.try_recv().ok());
+    }
+
+    /// Returns whether the channel is empty and every sender is gone.
+    pub fn is_disconnected(&self) -> bool {
+        let state = self.shared.lock();
+        return state.senders == 0 && state.queue.is_empty();
+    }
+
+    /// Returns how many values are waiting in the channel.
+    pub fn len(&self) -> usize {
+        return self.shared.lock().queue.len();
+    }
+
+    /// Returns whether there are no values waiting in the channel.
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Returns how many values the channel can hold, or `None` if it's
+    /// unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        return self.shared.capacity;
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        return Sender {
+            shared: self.shared.clone(),
+        };
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().receivers += 1;
+        return Receiver {
+            shared: self.shared.clone(),
+        };
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.wake_receivers(&state, true);
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            self.shared.not_full.notify_all();
+        }
+    }
+}
+
+/// Waits until one of `receivers` has a value and takes it. Returns the
+/// index of that receiver along with the value, or `RecvError::DISCONNECTED`
+/// if the receiver at that index is disconnected. If several are ready, the
+/// first one wins. Returns `None` if `receivers` is empty.
+pub fn select<T>(receivers: &[&Receiver<T>]) -> Option<(usize, Result<T, RecvError>)> {
+    return select_until(receivers, None);
+}
+
+/// Same as `select`, but gives up and returns `None` if none of the
+/// receivers become ready within `timeout`.
+pub fn select_timeout<T>(
+    receivers: &[&Receiver<T>],
+    timeout: Duration,
+) -> Option<(usize, Result<T, RecvError>)> {
+    return select_until(receivers, Some(Instant::now() + timeout));
+}
+
+fn select_until<T>(
+    receivers: &[&Receiver<T>],
+    deadline: Option<Instant>,
+) -> Option<(usize, Result<T, RecvError>)> {
+    if receivers.is_empty() {
+        return None;
+    }
+    let signal = Arc::new(Signal {
+        ready: Mutex::new(false),
+        cond: Condvar::new(),
+    });
+    for receiver in receivers {
+        receiver.shared.lock().selectors.push(signal.clone());
+    }
+
+    let result = loop {
+        // anything sent from here on sets the signal, so nothing that comes
+        // in after the check below can be missed.
+        *signal.ready.lock().unwrap_or_else(|e| e.into_inner()) = false;
+        let ready = receivers
+            .iter()
+            .enumerate()
+            .find_map(|(i, r)| match r.try_recv() {
+                Err(RecvError::EMPTY) => None,
+                result => Some((i, result)),
+            });
+        if ready.is_some() {
+            break ready;
+        }
+
+        let mut ready = signal.ready.lock().unwrap_or_else(|e| e.into_inner());
+        while !*ready {
+            ready = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    match signal.cond.wait_timeout(ready, deadline - now) {
+                        Ok((ready, _)) => ready,
+                        Err(e) => e.into_inner().0,
+                    }
+                }
+                None => signal.cond.wait(ready).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+        if !*ready {
+            break None;
+        }
+    };
+
+    for receiver in receivers {
+        receiver
+            .shared
+            .lock()
+            .selectors
+            .retain(|s| !Arc::ptr_eq(s, &signal));
+    }
+    return result;
+}
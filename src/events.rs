@@ -0,0 +1,259 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::BTreeMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use crate::{log::VANESSA_LOGGER, panic_message, serror};
+
+/// A type-erased event handler. It only ever gets events of the type it
+/// subscribed to.
+type Handler = Arc<dyn Fn(Arc<dyn Any + Send + Sync>) + Send + Sync + 'static>;
+
+/// How a handler gets its events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Right away, on the thread that published the event. `publish` doesn't
+    /// return until the handler is done.
+    SYNC,
+    /// As a task on the global worker pool, see `worker::bg`.
+    #[cfg(feature = "workers")]
+    BACKGROUND,
+}
+
+struct Subscriber {
+    id: usize,
+    delivery: Delivery,
+    handler: Handler,
+}
+
+/// A bus that events get published on. Handlers subscribe to a type of event
+/// and get every event of that type published from then on. Publishers and
+/// subscribers don't have to know anything about each other.
+/// The free functions in this module use a global bus, but you can make your
+/// own if you want to keep some events separate.
+pub struct EventBus {
+    subscribers: RwLock<BTreeMap<TypeId, Vec<Subscriber>>>,
+    next_id: AtomicUsize,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        return EventBus::new();
+    }
+}
+
+/// Unsubscribes its handler from the bus when dropped. Use `forget` to keep
+/// the handler around for as long as the bus is.
+#[must_use = "dropping a subscription unsubscribes its handler right away"]
+pub struct Subscription<'bus> {
+    bus: &'bus EventBus,
+    event: TypeId,
+    id: usize,
+    active: bool,
+}
+
+impl Subscription<'_> {
+    /// Unsubscribes the handler. Same as dropping the subscription.
+    pub fn unsubscribe(self) {}
+
+    /// Keeps the handler subscribed for good.
+    pub fn forget(mut self) {
+        self.active = false;
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        if self.active {
+            self.bus.unsubscribe(self.event, self.id);
+        }
+    }
+}
+
+impl EventBus {
+    /// Creates a bus with no subscribers.
+    pub const fn new() -> EventBus {
+        return EventBus {
+            subscribers: RwLock::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(0),
+        };
+    }
+
+    /// Subscribes `handler` to events of type `E`, delivered synchronously.
+    pub fn subscribe<E, F>(&self, handler: F) -> Subscription<'_>
+    where
+        E: Send + Sync + 'static,
+        F: Fn(&E) + Send + Sync + 'static,
+    {
+        return self.subscribe_with(Delivery::SYNC, handler);
+    }
+
+    /// Subscribes `handler` to events of type `E`, delivered on the global
+    /// worker pool.
+    #[cfg(feature = "workers")]
+    pub fn subscribe_bg<E, F>(&self, handler: F) -> Subscription<'_>
+    where
+        E: Send + Sync + 'static,
+        F: Fn(&E) + Send + Sync + 'static,
+    {
+        return self.subscribe_with(Delivery::BACKGROUND, handler);
+    }
+
+    /// Subscribes `handler` to events of type `E`, delivered however
+    /// `delivery` says.
+    pub fn subscribe_with<E, F>(&self, delivery: Delivery, handler: F) -> Subscription<'_>
+    where
+        E: Send + Sync + 'static,
+        F: Fn(&E) + Send + Sync + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let handler: Handler = Arc::new(move |event| {
+            if let Some(event) = event.downcast_ref::<E>() {
+                handler(event);
+            }
+        });
+        let event = TypeId::of::<E>();
+        match self.subscribers.write() {
+            Ok(mut subscribers) => subscribers.entry(event).or_default().push(Subscriber {
+                id,
+                delivery,
+                handler,
+            }),
+            Err(_) => serror!(
+                VANESSA_LOGGER,
+                "Failed to lock the event bus, cannot subscribe to {}.",
+                type_name::<E>()
+            ),
+        }
+        return Subscription {
+            bus: self,
+            event,
+            id,
+            active: true,
+        };
+    }
+
+    fn unsubscribe(&self, event: TypeId, id: usize) {
+        if let Ok(mut subscribers) = self.subscribers.write() {
+            if let Some(list) = subscribers.get_mut(&event) {
+                list.retain(|s| s.id != id);
+                if list.is_empty() {
+                    subscribers.remove(&event);
+                }
+            }
+        }
+    }
+
+    /// Publishes `event` to every handler subscribed to its type, and
+    /// returns how many handlers it was handed to. Handlers that panic or
+    /// can't be submitted to the pool have that logged, and don't stop the
+    /// event from reaching the other handlers.
+    pub fn publish<E>(&self, event: E) -> usize
+    where
+        E: Send + Sync + 'static,
+    {
+        // the handlers are called without holding the lock, so they can
+        // subscribe and publish themselves.
+        let handlers: Vec<(Delivery, Handler)> = match self.subscribers.read() {
+            Ok(subscribers) => match subscribers.get(&TypeId::of::<E>()) {
+                Some(list) => list
+                    .iter()
+                    .map(|s| (s.delivery, s.handler.clone()))
+                    .collect(),
+                None => return 0,
+            },
+            Err(_) => {
+                serror!(
+                    VANESSA_LOGGER,
+                    "Failed to lock the event bus, cannot publish {}.",
+                    type_name::<E>()
+                );
+                return 0;
+            }
+        };
+
+        let event: Arc<dyn Any + Send + Sync> = Arc::new(event);
+        let mut delivered = 0;
+        for (delivery, handler) in handlers {
+            match delivery {
+                Delivery::SYNC => {
+                    deliver(&handler, event.clone(), type_name::<E>());
+                    delivered += 1;
+                }
+                #[cfg(feature = "workers")]
+                Delivery::BACKGROUND => {
+                    let event = event.clone();
+                    let submitted = crate::worker::bg(move || {
+                        deliver(&handler, event, type_name::<E>());
+                    });
+                    match submitted {
+                        Ok(_) => delivered += 1,
+                        Err(e) => serror!(
+                            VANESSA_LOGGER,
+                            "Failed to deliver {} to a background handler: {e:?}",
+                            type_name::<E>()
+                        ),
+                    }
+                }
+            }
+        }
+        return delivered;
+    }
+
+    /// Returns how many handlers are subscribed to events of type `E`.
+    pub fn subscribers<E: 'static>(&self) -> usize {
+        return match self.subscribers.read() {
+            Ok(subscribers) => subscribers.get(&TypeId::of::<E>()).map_or(0, |l| l.len()),
+            Err(_) => 0,
+        };
+    }
+}
+
+/// Hands `event` to `handler`, logging it if the handler panics.
+fn deliver(handler: &Handler, event: Arc<dyn Any + Send + Sync>, name: &str) {
+    if let Err(payload) = catch_unwind(AssertUnwindSafe(|| handler(event))) {
+        let message = panic_message(&payload);
+        serror!(VANESSA_LOGGER, "A handler for {name} panicked: {message}");
+    }
+}
+
+static GLOBAL_BUS: EventBus = EventBus::new();
+
+/// Returns the global event bus, which the free functions in this module use.
+pub fn global() -> &'static EventBus {
+    return &GLOBAL_BUS;
+}
+
+/// Subscribes `handler` to events of type `E` on the global bus. See
+/// `EventBus::subscribe`.
+pub fn subscribe<E, F>(handler: F) -> Subscription<'static>
+where
+    E: Send + Sync + 'static,
+    F: Fn(&E) + Send + Sync + 'static,
+{
+    return GLOBAL_BUS.subscribe(handler);
+}
+
+/// Subscribes `handler` to events of type `E` on the global bus, delivered on
+/// the global worker pool. See `EventBus::subscribe_bg`.
+#[cfg(feature = "workers")]
+pub fn subscribe_bg<E, F>(handler: F) -> Subscription<'static>
+where
+    E: Send + Sync + 'static,
+    F: Fn(&E) + Send + Sync + 'static,
+{
+    return GLOBAL_BUS.subscribe_bg(handler);
+}
+
+/// Publishes `event` on the global bus. See `EventBus::publish`.
+pub fn publish<E>(event: E) -> usize
+where
+    E: Send + Sync + 'static,
+{
+    return GLOBAL_BUS.publish(event);
+}
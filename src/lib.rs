@@ -1,3 +1,6 @@
+// we like our explicit returns and 'static lifetimes around here.
+#![allow(clippy::needless_return, clippy::redundant_static_lifetimes)]
+
 #[cfg(test)]
 mod test;
 
@@ -12,11 +15,46 @@ pub fn full_init() {
     }
 }
 
+/// Pulls the message out of a panic payload, if it has one.
+pub(crate) fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    return "<no panic message>".into();
+}
+
+/// This module provides actors: state machines that handle messages one at a
+/// time on the background workers.
+#[cfg(feature = "workers")]
+pub mod actor;
+
+/// This module provides multi-producer multi-consumer channels. It does not
+/// have an initialization step.
+pub mod channel;
+
+/// This module provides a publish/subscribe bus for events. It does not have
+/// an initialization step.
+pub mod events;
+
 /// This module provides the logging facilities of the Vanessa Runtime.
+#[allow(
+    clippy::useless_format,
+    clippy::collapsible_if,
+    clippy::manual_strip,
+    mismatched_lifetime_syntaxes
+)]
 pub mod log;
 
+/// This module provides synchronization primitives for coordinating threads
+/// and background tasks. It does not have an initialization step.
+pub mod sync;
+
 /// This module deals with handling time. It does not have an initialization
 /// step.
+#[allow(clippy::manual_is_multiple_of, clippy::clone_on_copy)]
 pub mod time;
 
 /// This module deals with background workers.
@@ -0,0 +1,326 @@
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "workers")]
+use crate::worker::{TaskError, TaskHandle, WorkerPool};
+
+/// Locks `mutex`, ignoring poisoning. Everything in here only keeps counters
+/// behind its locks, and those are never left half-updated.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    return mutex.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+/// Waits on `cond` until `done` returns true. If there's a `deadline`, gives
+/// up once it has passed. Returns the guard and whether `done` returned true.
+fn wait_until<'a, T>(
+    cond: &Condvar,
+    mut guard: MutexGuard<'a, T>,
+    deadline: Option<Instant>,
+    mut done: impl FnMut(&T) -> bool,
+) -> (MutexGuard<'a, T>, bool) {
+    while !done(&guard) {
+        guard = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return (guard, false);
+                }
+                match cond.wait_timeout(guard, deadline - now) {
+                    Ok((guard, _)) => guard,
+                    Err(e) => e.into_inner().0,
+                }
+            }
+            None => cond.wait(guard).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+    return (guard, true);
+}
+
+/// Lets threads wait until some number of things have happened. Once the
+/// count reaches zero it stays there, and every waiter is let through.
+pub struct CountDownLatch {
+    count: Mutex<usize>,
+    zero: Condvar,
+}
+
+impl CountDownLatch {
+    /// Creates a latch that lets waiters through after `count` count downs.
+    pub fn new(count: usize) -> CountDownLatch {
+        return CountDownLatch {
+            count: Mutex::new(count),
+            zero: Condvar::new(),
+        };
+    }
+
+    /// Decrements the count, letting the waiters through if it hits zero.
+    /// Does nothing if the count is already zero.
+    pub fn count_down(&self) {
+        let mut count = lock(&self.count);
+        if *count == 0 {
+            return;
+        }
+        *count -= 1;
+        if *count == 0 {
+            self.zero.notify_all();
+        }
+    }
+
+    /// Returns the current count.
+    pub fn count(&self) -> usize {
+        return *lock(&self.count);
+    }
+
+    /// Blocks until the count reaches zero.
+    pub fn wait(&self) {
+        let _ = wait_until(&self.zero, lock(&self.count), None, |c| *c == 0);
+    }
+
+    /// Blocks until the count reaches zero or `timeout` passes. Returns
+    /// whether the count reached zero.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        return wait_until(&self.zero, lock(&self.count), Some(deadline), |c| *c == 0).1;
+    }
+}
+
+struct WaitGroupState {
+    count: Mutex<usize>,
+    zero: Condvar,
+}
+
+/// Keeps track of a group of jobs so you can wait for all of them to be
+/// done. Jobs can be added at any time, and clones share the same group.
+/// Tasks started with `WaitGroup::bg` are added and marked done on their
+/// own, even if they panic or never run.
+#[derive(Clone)]
+pub struct WaitGroup {
+    state: Arc<WaitGroupState>,
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        return WaitGroup::new();
+    }
+}
+
+impl WaitGroup {
+    /// Creates an empty wait group.
+    pub fn new() -> WaitGroup {
+        return WaitGroup {
+            state: Arc::new(WaitGroupState {
+                count: Mutex::new(0),
+                zero: Condvar::new(),
+            }),
+        };
+    }
+
+    /// Adds `n` jobs to the group.
+    pub fn add(&self, n: usize) {
+        *lock(&self.state.count) += n;
+    }
+
+    /// Marks one job as done.
+    pub fn done(&self) {
+        let mut count = lock(&self.state.count);
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            self.state.zero.notify_all();
+        }
+    }
+
+    /// Returns how many jobs aren't done yet.
+    pub fn count(&self) -> usize {
+        return *lock(&self.state.count);
+    }
+
+    /// Blocks until every job is done.
+    pub fn wait(&self) {
+        let _ = wait_until(&self.state.zero, lock(&self.state.count), None, |c| *c == 0);
+    }
+
+    /// Blocks until every job is done or `timeout` passes. Returns whether
+    /// every job got done.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let count = lock(&self.state.count);
+        return wait_until(&self.state.zero, count, Some(deadline), |c| *c == 0).1;
+    }
+
+    /// Adds a job to the group and returns a guard that marks it done when
+    /// dropped.
+    pub fn enter(&self) -> WaitGroupGuard {
+        self.add(1);
+        return WaitGroupGuard {
+            group: self.clone(),
+        };
+    }
+
+    /// Submits `f` to the global pool as part of this group. See `worker::bg`.
+    #[cfg(feature = "workers")]
+    pub fn bg<F, T>(&self, f: F) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        return self.bg_on(crate::worker::global(), f);
+    }
+
+    /// Submits `f` to `pool` as part of this group. See `WorkerPool::bg`.
+    #[cfg(feature = "workers")]
+    pub fn bg_on<F, T>(&self, pool: &WorkerPool, f: F) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // the guard lives in the closure, so the job is marked done however
+        // the closure goes away: run, panicked, cancelled or rejected.
+        let guard = self.enter();
+        return pool.bg(move || {
+            let _guard = guard;
+            return f();
+        });
+    }
+}
+
+/// Marks a job of a `WaitGroup` as done when dropped.
+pub struct WaitGroupGuard {
+    group: WaitGroup,
+}
+
+impl Drop for WaitGroupGuard {
+    fn drop(&mut self) {
+        self.group.done();
+    }
+}
+
+/// Limits how many threads can do something at the same time. Threads
+/// acquire a permit before doing it, and give it back by dropping it.
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` permits to hand out.
+    pub fn new(permits: usize) -> Semaphore {
+        return Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        };
+    }
+
+    /// Blocks until a permit is available and takes it.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        let (mut permits, _) = wait_until(&self.released, lock(&self.permits), None, |p| *p > 0);
+        *permits -= 1;
+        return SemaphorePermit { semaphore: self };
+    }
+
+    /// Takes a permit if one is available right now.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut permits = lock(&self.permits);
+        if *permits == 0 {
+            return None;
+        }
+        *permits -= 1;
+        return Some(SemaphorePermit { semaphore: self });
+    }
+
+    /// Waits up to `timeout` for a permit. Returns `None` if none became
+    /// available in time.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<SemaphorePermit<'_>> {
+        let deadline = Instant::now() + timeout;
+        let (mut permits, acquired) =
+            wait_until(&self.released, lock(&self.permits), Some(deadline), |p| {
+                *p > 0
+            });
+        if !acquired {
+            return None;
+        }
+        *permits -= 1;
+        return Some(SemaphorePermit { semaphore: self });
+    }
+
+    /// Returns how many permits are available right now.
+    pub fn available(&self) -> usize {
+        return *lock(&self.permits);
+    }
+
+    /// Adds `n` more permits.
+    pub fn add_permits(&self, n: usize) {
+        *lock(&self.permits) += n;
+        self.released.notify_all();
+    }
+
+    fn release(&self) {
+        *lock(&self.permits) += 1;
+        self.released.notify_one();
+    }
+}
+
+/// A permit taken from a `Semaphore`. It's given back when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+struct BarrierState {
+    /// How many threads are waiting in the current round.
+    waiting: usize,
+    /// Bumped every time the barrier trips, so waiters know their round is
+    /// over.
+    generation: usize,
+}
+
+/// Makes a fixed number of threads wait for each other. Once all of them
+/// have called `wait`, they're all let through and the barrier can be used
+/// again for the next round.
+pub struct Barrier {
+    parties: usize,
+    state: Mutex<BarrierState>,
+    tripped: Condvar,
+}
+
+impl Barrier {
+    /// Creates a barrier for `parties` threads. A barrier for 0 threads
+    /// works like one for 1.
+    pub fn new(parties: usize) -> Barrier {
+        return Barrier {
+            parties: parties.max(1),
+            state: Mutex::new(BarrierState {
+                waiting: 0,
+                generation: 0,
+            }),
+            tripped: Condvar::new(),
+        };
+    }
+
+    /// Returns how many threads have to wait before the barrier trips.
+    pub fn parties(&self) -> usize {
+        return self.parties;
+    }
+
+    /// Blocks until all parties are waiting. Returns true for exactly one
+    /// of them each round (the last one to arrive), which is handy if
+    /// someone has to do some work in between rounds.
+    pub fn wait(&self) -> bool {
+        let mut state = lock(&self.state);
+        state.waiting += 1;
+        if state.waiting == self.parties {
+            state.waiting = 0;
+            state.generation = state.generation.wrapping_add(1);
+            self.tripped.notify_all();
+            return true;
+        }
+        let generation = state.generation;
+        let _ = wait_until(&self.tripped, state, None, |s| s.generation != generation);
+        return false;
+    }
+}
@@ -51,6 +51,964 @@ fn time_interface() {
 fn worker_interface() {
     use vanessa::worker::*;
     init();
-    bg(|| {});
+    bg(|| {}).unwrap();
+    let handle = bg(|| 21 * 2).unwrap();
+    assert_eq!(handle.require(), Ok(42));
+    let handle = bg(|| panic!("oh no")).unwrap();
+    assert_eq!(handle.require(), Err(TaskError::PANICKED("oh no".into())));
+    // the worker that ran the panicking task should still be around
+    assert_eq!(bg(|| 1).unwrap().require(), Ok(1));
+    let (send, recv) = std::sync::mpsc::channel::<()>();
+    let mut handle = bg(move || recv.recv().ok()).unwrap();
+    assert!(!handle.is_done());
+    assert_eq!(handle.try_require(), None);
+    assert_eq!(
+        handle.require_timeout(std::time::Duration::from_millis(10)),
+        None
+    );
+    send.send(()).unwrap();
+    assert_eq!(
+        handle.require_timeout(std::time::Duration::from_secs(5)),
+        Some(Ok(Some(())))
+    );
+    assert!(handle.is_done());
     shutdown_blocking();
+    // the global pool should come back after a shutdown too
+    init_with(1);
+    assert_eq!(bg(|| 3).unwrap().require(), Ok(3));
+    shutdown_blocking();
+}
+
+#[test]
+/// Pools other than the global one should be usable side by side.
+fn worker_pools() {
+    use vanessa::worker::*;
+    let cpu = WorkerPool::new("cpu", 2);
+    let io = WorkerPool::new("io", 1);
+    assert_eq!(cpu.name(), "cpu");
+    let a = cpu.bg(|| 1).unwrap();
+    let b = io.bg(|| 2).unwrap();
+    assert_eq!(a.require(), Ok(1));
+    assert_eq!(b.require(), Ok(2));
+    io.shutdown_blocking();
+    assert_eq!(io.bg(|| {}).err(), Some(TaskError::POOLNOTINITIALIZED));
+    cpu.shutdown_blocking();
+}
+
+#[test]
+/// Cancelled tasks shouldn't run if they haven't started, and running ones
+/// should be able to notice.
+fn worker_cancellation() {
+    use std::sync::mpsc::channel;
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("cancel", 1);
+
+    // keep the only worker busy so the next task stays queued
+    let (send, recv) = channel::<()>();
+    let blocker = pool.bg(move || recv.recv().ok()).unwrap();
+    let (ran_send, ran_recv) = channel::<()>();
+    let queued = pool.bg(move || ran_send.send(()).ok()).unwrap();
+    queued.cancel();
+    send.send(()).unwrap();
+    blocker.require().unwrap();
+    assert_eq!(queued.require(), Err(TaskError::CANCELLED));
+    assert!(ran_recv.try_recv().is_err());
+
+    let running = pool
+        .bg(|| {
+            while !is_cancelled() {
+                std::thread::yield_now();
+            }
+        })
+        .unwrap();
+    running.cancel_token().cancel();
+    assert_eq!(running.require(), Err(TaskError::CANCELLED));
+    assert!(!is_cancelled());
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Idle workers should always pick the most urgent task first.
+fn worker_priorities() {
+    use std::sync::{mpsc::channel, Arc, Mutex};
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("priorities", 1);
+
+    let (send, recv) = channel::<()>();
+    let blocker = pool.bg(move || recv.recv().ok()).unwrap();
+    let order = Arc::new(Mutex::new(vec![]));
+    let mut handles = vec![];
+    for priority in [Priority::LOW, Priority::NORMAL, Priority::HIGH] {
+        let order = order.clone();
+        handles.push(
+            pool.bg_with_priority(priority, move || order.lock().unwrap().push(priority))
+                .unwrap(),
+        );
+    }
+    send.send(()).unwrap();
+    blocker.require().unwrap();
+    for handle in handles {
+        handle.require().unwrap();
+    }
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec![Priority::HIGH, Priority::NORMAL, Priority::LOW]
+    );
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Parallel helpers should hand results back in input order.
+fn worker_parallel_helpers() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("parallel", 3);
+    assert_eq!(pool.threads(), 3);
+
+    let squares = pool.par_map(0..10, |n: usize| n * n).unwrap();
+    assert_eq!(squares, (0..10).map(|n| n * n).collect::<Vec<_>>());
+
+    let sum = Arc::new(AtomicUsize::new(0));
+    let counter = sum.clone();
+    pool.par_for_each(1..=100, move |n| {
+        counter.fetch_add(n, Ordering::Relaxed);
+    })
+    .unwrap();
+    assert_eq!(sum.load(Ordering::Relaxed), 5050);
+
+    let sums = pool
+        .par_chunks(1..=10, 4, |chunk: Vec<i32>| chunk.iter().sum::<i32>())
+        .unwrap();
+    assert_eq!(sums, vec![10, 26, 19]);
+    assert_eq!(pool.par_map(Vec::<i32>::new(), |n| n).unwrap(), vec![]);
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Scoped tasks should be able to borrow from the stack and be done by the
+/// time the scope returns.
+fn worker_scope() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("scope", 2);
+
+    let numbers = vec![1, 2, 3, 4];
+    let total = AtomicUsize::new(0);
+    let handle = pool.scope(|s| {
+        for n in &numbers {
+            let total = &total;
+            s.bg(move || {
+                total.fetch_add(*n, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+        s.bg(|| numbers.len()).unwrap()
+    });
+    assert_eq!(total.load(Ordering::Relaxed), 10);
+    assert_eq!(handle.require(), Ok(4));
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Delayed and periodic tasks should run on time and stop when cancelled.
+fn worker_scheduling() {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("scheduling", 1);
+
+    let start = Instant::now();
+    let delayed = pool.bg_after(Duration::from_millis(30), || 7).unwrap();
+    assert_eq!(delayed.require(), Ok(7));
+    assert!(start.elapsed() >= Duration::from_millis(30));
+
+    let never = pool.bg_after(Duration::from_secs(60), || 7).unwrap();
+    never.cancel();
+    assert_eq!(never.require(), Err(TaskError::CANCELLED));
+
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let counter = ticks.clone();
+    let periodic = pool
+        .bg_every(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+    while ticks.load(Ordering::Relaxed) < 3 {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    periodic.cancel();
+    assert_eq!(periodic.require(), Err(TaskError::CANCELLED));
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Shutting down with a deadline should account for every task and worker.
+fn worker_shutdown_with_timeout() {
+    use std::{sync::mpsc::channel, time::Duration};
+    use vanessa::worker::*;
+
+    let pool = WorkerPool::new("drain", 2);
+    let handles: Vec<_> = (0..4).map(|n| pool.bg(move || n).unwrap()).collect();
+    let report = pool.shutdown_with_timeout(Duration::from_secs(5), ShutdownPolicy::DRAIN);
+    assert_eq!(report.completed, 4);
+    assert_eq!(report.abandoned, 0);
+    assert!(report.stragglers.is_empty());
+    for (n, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.require(), Ok(n));
+    }
+
+    let pool = WorkerPool::new("reject", 1);
+    let (send, recv) = channel::<()>();
+    let (started_send, started_recv) = channel::<()>();
+    let blocker = pool
+        .bg(move || {
+            started_send.send(()).ok();
+            recv.recv().ok()
+        })
+        .unwrap();
+    let queued = pool.bg(|| {}).unwrap();
+    started_recv.recv().unwrap();
+    let report = pool.shutdown_with_timeout(Duration::from_millis(20), ShutdownPolicy::REJECT);
+    assert_eq!(report.completed, 0);
+    assert_eq!(report.abandoned, 1);
+    assert_eq!(report.stragglers, vec![0]);
+    assert_eq!(queued.require(), Err(TaskError::SHUTDOWN));
+    send.send(()).unwrap();
+    assert_eq!(blocker.require(), Ok(Some(())));
+}
+
+#[test]
+/// Pools should be resizable while running and restartable after a shutdown.
+fn worker_resize_and_restart() {
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("resize", 1);
+    pool.resize(3);
+    assert_eq!(pool.threads(), 3);
+    assert_eq!(
+        pool.par_map(0..6, |n: i32| n + 1).unwrap(),
+        vec![1, 2, 3, 4, 5, 6]
+    );
+    pool.resize(1);
+    assert_eq!(pool.threads(), 1);
+    assert_eq!(
+        pool.bg(|| "still here").unwrap().require(),
+        Ok("still here")
+    );
+
+    pool.shutdown_blocking();
+    assert_eq!(pool.threads(), 0);
+    pool.start(2);
+    assert_eq!(pool.threads(), 2);
+    assert_eq!(pool.bg(|| 5).unwrap().require(), Ok(5));
+    pool.shutdown_blocking();
+
+    // with no workers left, shutting down throws away what's queued
+    pool.start(1);
+    pool.resize(0);
+    let stranded = pool.bg(|| 1).unwrap();
+    pool.shutdown_blocking();
+    assert_eq!(stranded.require(), Err(TaskError::SHUTDOWN));
+    let empty = WorkerPool::new("empty", 0);
+    let stranded = empty.bg(|| 1).unwrap();
+    drop(empty);
+    assert_eq!(stranded.require(), Err(TaskError::SHUTDOWN));
+}
+
+#[test]
+/// Stats should keep count of what the pool has done.
+fn worker_stats() {
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("stats", 2);
+    for _ in 0..5 {
+        pool.bg(|| {}).unwrap().require().unwrap();
+    }
+    pool.bg(|| panic!("counted")).unwrap().require().ok();
+
+    // handles hear back a moment before the counters get bumped
+    let mut stats = pool.stats();
+    while stats.completed + stats.failed < 6 {
+        std::thread::yield_now();
+        stats = pool.stats();
+    }
+    assert_eq!(stats.name, "stats");
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.completed, 5);
+    assert_eq!(stats.failed, 1);
+    assert_eq!(stats.workers.len(), 2);
+    assert_eq!(stats.workers.iter().map(|w| w.tasks).sum::<usize>(), 6);
+    assert!(stats.latency.p50 <= stats.latency.p99);
+    assert!(stats.latency.p99 <= stats.latency.max);
+    assert!(format!("{stats}").starts_with("Pool 'stats'"));
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Tasks that are still running while the pool shuts down should be able to
+/// use the pool without holding up the shutdown.
+fn worker_shutdown_from_tasks() {
+    use std::{
+        sync::{mpsc, Arc},
+        time::Duration,
+    };
+    use vanessa::worker::*;
+    let pool = Arc::new(WorkerPool::new("shutdown-stats", 2));
+    let p = pool.clone();
+    let name = pool
+        .bg(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            return p.stats().name;
+        })
+        .unwrap();
+
+    let (done, finished) = mpsc::channel();
+    std::thread::spawn(move || {
+        pool.shutdown_blocking();
+        done.send(()).unwrap();
+    });
+    assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+    assert_eq!(name.require(), Ok("shutdown-stats".to_string()));
+}
+
+#[test]
+/// Continuations and dependencies should run in the right order without
+/// tying up workers.
+fn worker_continuations() {
+    use std::sync::{Arc, Mutex};
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("continuations", 1);
+
+    let doubled = pool.bg(|| 21).unwrap().then(|n| n * 2);
+    assert_eq!(doubled.require(), Ok(42));
+    let failed = pool
+        .bg(|| -> i32 { panic!("nope") })
+        .unwrap()
+        .then(|n| n * 2);
+    assert_eq!(failed.require(), Err(TaskError::PANICKED("nope".into())));
+
+    // a -> (b, c) -> d
+    let order = Arc::new(Mutex::new(vec![]));
+    let step = |name: &'static str| {
+        let order = order.clone();
+        move || order.lock().unwrap().push(name)
+    };
+    let a = pool.bg(step("a")).unwrap();
+    let b = pool.bg_when(&[a.dependency()], step("b")).unwrap();
+    let c = pool.bg_when(&[a.dependency()], step("c")).unwrap();
+    let d = pool
+        .bg_when(&[b.dependency(), c.dependency()], step("d"))
+        .unwrap();
+    d.require().unwrap();
+    let order = order.lock().unwrap();
+    assert_eq!(order[0], "a");
+    assert_eq!(order[3], "d");
+
+    let broken = pool.bg(|| panic!("broken")).unwrap();
+    let after = pool.bg_when(&[broken.dependency()], || {}).unwrap();
+    assert_eq!(after.require(), Err(TaskError::DEPENDENCYFAILED));
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// join_all should hand back results in order, select should hand back
+/// whichever task finishes first.
+fn worker_join_and_select() {
+    use std::time::Duration;
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("join", 2);
+
+    let handles = (0..5).map(|i| pool.bg(move || i * 10).unwrap()).collect();
+    assert_eq!(
+        join_all(handles),
+        vec![Ok(0), Ok(10), Ok(20), Ok(30), Ok(40)]
+    );
+
+    let slow = pool.bg_after(Duration::from_secs(10), || 1).unwrap();
+    let token = slow.cancel_token();
+    let fast = pool.bg(|| 2).unwrap();
+    let results = join_all_timeout(vec![fast, slow], Duration::from_millis(100));
+    assert_eq!(results, vec![Ok(2), Err(TaskError::TIMEDOUT)]);
+    token.cancel();
+
+    let mut handles = vec![
+        pool.bg_after(Duration::from_secs(10), || "slow").unwrap(),
+        pool.bg(|| "fast").unwrap(),
+    ];
+    assert_eq!(select(&mut handles), Some((1, Ok("fast"))));
+    assert_eq!(handles.len(), 1);
+    assert_eq!(
+        select_timeout(&mut handles, Duration::from_millis(50)),
+        None
+    );
+    // polling shouldn't leave anything behind on the handles
+    for _ in 0..100 {
+        assert_eq!(select_timeout(&mut handles, Duration::ZERO), None);
+    }
+    assert_eq!(handles[0].listeners(), 0);
+    handles[0].cancel();
+    assert_eq!(select(&mut handles), Some((0, Err(TaskError::CANCELLED))));
+    assert_eq!(select(&mut handles), None);
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Pools made with a builder should name their threads and run the hooks.
+fn worker_builder() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use vanessa::worker::*;
+    let started = Arc::new(AtomicUsize::new(0));
+    let stopped = Arc::new(AtomicUsize::new(0));
+    let (s, t) = (started.clone(), stopped.clone());
+    let pool = Builder::new()
+        .name("built")
+        .threads(2)
+        .thread_name("builder-test")
+        .stack_size(4 * 1024 * 1024)
+        .on_thread_start(move |_| {
+            s.fetch_add(1, Ordering::SeqCst);
+        })
+        .on_thread_stop(move |_| {
+            t.fetch_add(1, Ordering::SeqCst);
+        })
+        .build();
+    assert_eq!(pool.name(), "built");
+
+    let name = pool
+        .bg(|| std::thread::current().name().unwrap().to_string())
+        .unwrap();
+    assert!(name.require().unwrap().starts_with("builder-test-"));
+    pool.shutdown_blocking();
+    assert_eq!(started.load(Ordering::SeqCst), 2);
+    assert_eq!(stopped.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+/// A full queue should push back on bg and make try_bg fail.
+fn worker_bounded_queue() {
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+    use vanessa::worker::*;
+    let pool = Arc::new(
+        Builder::new()
+            .name("bounded")
+            .threads(1)
+            .capacity(2)
+            .build(),
+    );
+
+    // keep the only worker busy so nothing gets taken off the queue
+    let gate = Arc::new(Barrier::new(2));
+    let g = gate.clone();
+    let blocker = pool.bg(move || g.wait()).unwrap();
+    while pool.stats().running == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let queued: Vec<_> = (0..2).map(|i| pool.try_bg(move || i).unwrap()).collect();
+    assert_eq!(pool.try_bg(|| 2).err(), Some(TaskError::QUEUEFULL));
+
+    // a blocking submit has to wait for the worker to make room
+    let p = pool.clone();
+    let producer = thread::spawn(move || p.bg(|| 3).unwrap().require());
+    thread::sleep(Duration::from_millis(50));
+    assert!(!producer.is_finished());
+    gate.wait();
+
+    blocker.require().unwrap();
+    assert_eq!(join_all(queued), vec![Ok(0), Ok(1)]);
+    assert_eq!(producer.join().unwrap(), Ok(3));
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Tasks should be able to tell which worker and task they're running as.
+fn worker_context() {
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("context", 1);
+    assert!(!is_worker_thread());
+    assert_eq!(current_worker_id(), None);
+    assert_eq!(current_task_id(), None);
+
+    let handle = pool
+        .bg(|| (is_worker_thread(), current_worker_id(), current_task_id()))
+        .unwrap();
+    let id = handle.id();
+    assert_eq!(handle.require(), Ok((true, Some(0), Some(id))));
+
+    let a = pool.bg(|| ()).unwrap();
+    let b = pool.bg(|| ()).unwrap();
+    assert_ne!(a.id(), b.id());
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// Pools with a watchdog should keep working normally while it warns about
+/// slow tasks.
+fn worker_watchdog() {
+    use std::time::Duration;
+    use vanessa::worker::*;
+    let pool = Builder::new()
+        .name("watched")
+        .threads(2)
+        .watchdog(Duration::from_millis(20))
+        .build();
+    let slow = pool
+        .bg(|| std::thread::sleep(Duration::from_millis(200)))
+        .unwrap();
+    let waiting = pool.bg(move || slow.require()).unwrap();
+    assert_eq!(waiting.require(), Ok(Ok(())));
+    // the sleeping task and the one waiting on it both ran too long
+    assert_eq!(pool.watchdog_reports(), (2, 0));
+    pool.resize(1);
+    assert_eq!(pool.bg(|| 1).unwrap().require(), Ok(1));
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// The watchdog should notice a pool whose only worker waits on a task that
+/// can never run.
+fn worker_watchdog_deadlock() {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use vanessa::worker::*;
+    let pool = Arc::new(
+        Builder::new()
+            .name("deadlocked")
+            .threads(1)
+            .watchdog(Duration::from_millis(200))
+            .build(),
+    );
+    let p = pool.clone();
+    let stuck = pool.bg(move || p.bg(|| 1).unwrap().require()).unwrap();
+
+    let start = Instant::now();
+    while pool.watchdog_reports().1 == 0 && start.elapsed() < Duration::from_secs(5) {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(pool.watchdog_reports().1, 1);
+
+    // throwing the queued task away is the only way out
+    pool.shutdown_with_timeout(Duration::from_secs(5), ShutdownPolicy::REJECT);
+    assert_eq!(stuck.require(), Ok(Err(TaskError::SHUTDOWN)));
+}
+
+#[test]
+/// Inline mode should run tasks right away, in order, without any pool.
+fn worker_inline() {
+    use std::sync::{Arc, Mutex};
+    use vanessa::worker::*;
+    let pool = WorkerPool::new("inline", 0);
+    pool.shutdown_blocking();
+    assert_eq!(pool.bg(|| ()).err(), Some(TaskError::POOLNOTINITIALIZED));
+
+    set_inline(true);
+    assert!(is_inline());
+    let order = Arc::new(Mutex::new(vec![]));
+    let mut handles = vec![];
+    for i in 0..5 {
+        let order = order.clone();
+        handles.push(pool.bg(move || order.lock().unwrap().push(i)).unwrap());
+        assert!(handles[i].is_done());
+    }
+    assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+
+    let doubled = pool.bg(|| 21).unwrap().then(|n| n * 2);
+    assert!(doubled.is_done());
+    let after = pool.bg_when(&[doubled.dependency()], || 1).unwrap();
+    assert_eq!(after.require(), Ok(1));
+    assert_eq!(doubled.require(), Ok(42));
+    assert_eq!(pool.par_map(0..4, |n| n + 1), Ok(vec![1, 2, 3, 4]));
+    set_inline(false);
+}
+
+#[test]
+/// Promises should be completable from any thread and work with everything
+/// that takes task handles.
+fn worker_promises() {
+    use std::time::Duration;
+    use vanessa::worker::*;
+
+    let (completer, mut pending) = promise::<i32>();
+    assert_eq!(pending.try_require(), None);
+    assert_eq!(pending.require_timeout(Duration::from_millis(10)), None);
+    std::thread::spawn(move || completer.complete(7));
+    assert_eq!(pending.require(), Ok(7));
+
+    let (completer, pending) = promise::<i32>();
+    completer.fail(TaskError::CANCELLED);
+    assert_eq!(pending.require(), Err(TaskError::CANCELLED));
+
+    let (completer, pending) = promise::<()>();
+    drop(completer);
+    assert_eq!(pending.require(), Err(TaskError::BROKENCHANNEL));
+
+    let (completer, mut pending) = promise::<&str>();
+    pending.cancel();
+    assert!(completer.is_cancelled());
+    // cancelling settles the promise, completing it afterwards does nothing
+    assert_eq!(
+        pending.require_timeout(Duration::from_secs(5)),
+        Some(Err(TaskError::CANCELLED))
+    );
+    completer.complete("too late");
+
+    let pool = WorkerPool::new("promises", 1);
+    let (completer, pending) = promise::<i32>();
+    let mut handles = vec![
+        pool.bg_after(Duration::from_secs(10), || 0).unwrap(),
+        pending,
+    ];
+    completer.complete(1);
+    assert_eq!(select(&mut handles), Some((1, Ok(1))));
+    handles[0].cancel();
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// A latch should only let waiters through once it's been counted down.
+fn sync_latch() {
+    use std::{sync::Arc, time::Duration};
+    use vanessa::sync::*;
+    let latch = Arc::new(CountDownLatch::new(3));
+    assert!(!latch.wait_timeout(Duration::from_millis(10)));
+    for _ in 0..3 {
+        let latch = latch.clone();
+        std::thread::spawn(move || latch.count_down());
+    }
+    latch.wait();
+    assert_eq!(latch.count(), 0);
+    latch.count_down();
+    assert!(latch.wait_timeout(Duration::ZERO));
+}
+
+#[test]
+/// A wait group should wait for every task that was started through it,
+/// including ones that panic or get cancelled.
+fn sync_wait_group() {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use vanessa::{sync::*, worker::*};
+    let pool = WorkerPool::new("waitgroup", 2);
+    let group = WaitGroup::new();
+    let count = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let count = count.clone();
+        group
+            .bg_on(&pool, move || {
+                std::thread::sleep(Duration::from_millis(1));
+                count.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+    }
+    group.bg_on(&pool, || panic!("oops")).unwrap();
+    group.wait();
+    assert_eq!(count.load(Ordering::SeqCst), 10);
+    assert_eq!(group.count(), 0);
+
+    let manual = group.enter();
+    assert!(!group.wait_timeout(Duration::from_millis(10)));
+    drop(manual);
+    assert!(group.wait_timeout(Duration::ZERO));
+    pool.shutdown_blocking();
+}
+
+#[test]
+/// A semaphore should never hand out more permits than it has.
+fn sync_semaphore() {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use vanessa::sync::*;
+    let semaphore = Arc::new(Semaphore::new(2));
+    let inside = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let (semaphore, inside, most) = (semaphore.clone(), inside.clone(), most.clone());
+            std::thread::spawn(move || {
+                let _permit = semaphore.acquire();
+                let now = inside.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(2));
+                inside.fetch_sub(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert!(most.load(Ordering::SeqCst) <= 2);
+
+    let a = semaphore.try_acquire().unwrap();
+    let _b = semaphore.try_acquire().unwrap();
+    assert!(semaphore.try_acquire().is_none());
+    assert!(semaphore
+        .acquire_timeout(Duration::from_millis(10))
+        .is_none());
+    drop(a);
+    assert_eq!(semaphore.available(), 1);
+    semaphore.add_permits(1);
+    assert_eq!(semaphore.available(), 2);
+}
+
+#[test]
+/// A barrier should hold everyone until all parties arrive, round after
+/// round, with exactly one leader per round.
+fn sync_barrier() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use vanessa::sync::*;
+    let barrier = Arc::new(Barrier::new(4));
+    let arrived = Arc::new(AtomicUsize::new(0));
+    let leaders = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let (barrier, arrived, leaders) = (barrier.clone(), arrived.clone(), leaders.clone());
+            std::thread::spawn(move || {
+                for round in 1..=3 {
+                    arrived.fetch_add(1, Ordering::SeqCst);
+                    if barrier.wait() {
+                        leaders.fetch_add(1, Ordering::SeqCst);
+                    }
+                    // nobody gets here before everyone arrived this round
+                    assert!(arrived.load(Ordering::SeqCst) >= round * 4);
+                    barrier.wait();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(leaders.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+/// Channels should hand every value to exactly one receiver, push back when
+/// full and notice when the other side is gone.
+fn channel_interface() {
+    use std::{thread, time::Duration};
+    use vanessa::channel::*;
+
+    let (send, recv) = unbounded();
+    let receivers: Vec<_> = (0..4)
+        .map(|_| {
+            let recv = recv.clone();
+            thread::spawn(move || recv.iter().sum::<usize>())
+        })
+        .collect();
+    let senders: Vec<_> = (0..4)
+        .map(|i| {
+            let send = send.clone();
+            thread::spawn(move || {
+                for n in 0..250 {
+                    send.send(i * 250 + n).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(send);
+    for s in senders {
+        s.join().unwrap();
+    }
+    let total: usize = receivers.into_iter().map(|r| r.join().unwrap()).sum();
+    assert_eq!(total, (0..1000).sum());
+    assert_eq!(recv.try_recv(), Err(RecvError::DISCONNECTED));
+
+    let (send, recv) = bounded(1);
+    send.send(1).unwrap();
+    assert_eq!(send.try_send(2), Err(SendError::FULL(2)));
+    assert_eq!(
+        send.send_timeout(2, Duration::from_millis(10)),
+        Err(SendError::TIMEDOUT(2))
+    );
+    assert_eq!(recv.recv(), Ok(1));
+    assert_eq!(
+        recv.recv_timeout(Duration::from_millis(10)),
+        Err(RecvError::TIMEDOUT)
+    );
+    assert_eq!(recv.try_recv(), Err(RecvError::EMPTY));
+    drop(recv);
+    assert!(send.is_disconnected());
+    assert_eq!(send.send(3), Err(SendError::DISCONNECTED(3)));
+
+    let (send_a, recv_a) = unbounded::<&str>();
+    let (send_b, recv_b) = bounded(4);
+    assert_eq!(
+        select_timeout(&[&recv_a, &recv_b], Duration::from_millis(10)),
+        None
+    );
+    let sender = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        send_b.send("b").unwrap();
+    });
+    assert_eq!(select(&[&recv_a, &recv_b]), Some((1, Ok("b"))));
+    sender.join().unwrap();
+    assert_eq!(
+        select(&[&recv_a, &recv_b]),
+        Some((1, Err(RecvError::DISCONNECTED)))
+    );
+    drop(send_a);
+}
+
+#[test]
+/// Actors should handle their messages one at a time and in order, and
+/// answer requests through their promises.
+fn actor_interface() {
+    use std::time::Duration;
+    use vanessa::{actor::*, worker::*};
+
+    struct Counter {
+        count: usize,
+        busy: bool,
+    }
+    enum Message {
+        Add(usize),
+        Get,
+        Panic,
+    }
+    impl Actor for Counter {
+        type Message = Message;
+        type Reply = usize;
+        fn handle(&mut self, message: Message) -> usize {
+            assert!(!self.busy, "handled two messages at once");
+            self.busy = true;
+            match message {
+                Message::Add(n) => {
+                    std::thread::sleep(Duration::from_micros(10));
+                    self.count += n;
+                }
+                Message::Get => {}
+                Message::Panic => {
+                    self.busy = false;
+                    panic!("boom");
+                }
+            }
+            self.busy = false;
+            return self.count;
+        }
+    }
+
+    let pool = WorkerPool::new("actors", 4);
+    let counter = spawn_on(
+        &pool,
+        Counter {
+            count: 0,
+            busy: false,
+        },
+    )
+    .unwrap();
+    let senders: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    counter.send(Message::Add(1)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for s in senders {
+        s.join().unwrap();
+    }
+    assert_eq!(counter.ask(Message::Get).unwrap().require(), Ok(200));
+    assert_eq!(
+        counter.ask(Message::Panic).unwrap().require(),
+        Err(TaskError::PANICKED("boom".into()))
+    );
+    assert_eq!(counter.ask(Message::Add(1)).unwrap().require(), Ok(201));
+
+    counter.stop();
+    assert!(counter.send(Message::Get).is_err());
+
+    // messages that can't be handled anymore shouldn't look like they were
+    // sent
+    let orphan = spawn_on(
+        &pool,
+        Counter {
+            count: 0,
+            busy: false,
+        },
+    )
+    .unwrap();
+    pool.shutdown_blocking();
+    assert!(orphan.send(Message::Add(1)).is_err());
+    assert!(orphan.ask(Message::Get).is_err());
+}
+
+#[test]
+/// Events should only reach handlers of their own type, and only for as long
+/// as those stay subscribed.
+fn events_interface() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use vanessa::{events::*, worker::set_inline};
+
+    struct ConfigReloaded;
+    struct JobFinished(usize);
+
+    let bus = EventBus::new();
+    let reloads = Arc::new(AtomicUsize::new(0));
+    let finished = Arc::new(AtomicUsize::new(0));
+    let r = reloads.clone();
+    let reload_sub = bus.subscribe(move |_: &ConfigReloaded| {
+        r.fetch_add(1, Ordering::SeqCst);
+    });
+    let f = finished.clone();
+    bus.subscribe(move |job: &JobFinished| {
+        f.fetch_add(job.0, Ordering::SeqCst);
+    })
+    .forget();
+    let _panicky = bus.subscribe(|_: &JobFinished| panic!("bad handler"));
+
+    assert_eq!(bus.publish(ConfigReloaded), 1);
+    assert_eq!(bus.publish(JobFinished(5)), 2);
+    assert_eq!(reloads.load(Ordering::SeqCst), 1);
+    assert_eq!(finished.load(Ordering::SeqCst), 5);
+
+    reload_sub.unsubscribe();
+    assert_eq!(bus.subscribers::<ConfigReloaded>(), 0);
+    assert_eq!(bus.publish(ConfigReloaded), 0);
+    assert_eq!(reloads.load(Ordering::SeqCst), 1);
+
+    // background handlers go through worker::bg, which inline mode runs
+    // right here.
+    set_inline(true);
+    let f = finished.clone();
+    let bg_sub = bus.subscribe_bg(move |job: &JobFinished| {
+        f.fetch_add(job.0 * 10, Ordering::SeqCst);
+    });
+    assert_eq!(bus.publish(JobFinished(1)), 3);
+    assert_eq!(finished.load(Ordering::SeqCst), 16);
+    drop(bg_sub);
+    set_inline(false);
+    assert_eq!(bus.subscribers::<JobFinished>(), 2);
 }
@@ -1,212 +1,1739 @@
 use std::{
-    num::NonZeroUsize,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    marker::PhantomData,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Condvar, Mutex, RwLock,
     },
+    time::Duration,
 };
 
-use crate::{log::VANESSA_LOGGER, sdebug, serror, swarn};
+use crate::{
+    log::{LogLevel, VANESSA_LOGGER},
+    panic_message, sdebug, serror, shyper, sinfo, swarn,
+    time::epoch_millis,
+};
+
+mod builder;
+mod promise;
+mod queue;
+mod stats;
+mod timer;
+mod watchdog;
+
+pub use builder::Builder;
+use builder::PoolConfig;
+pub use promise::{promise, Completer, Promise};
+use queue::TaskQueue;
+use stats::WorkerCounters;
+pub use stats::{LatencyStats, PoolStats, WorkerStats};
+use watchdog::Watchdog;
+
+/// A pool of background worker threads. The free functions in this module
+/// all go through the global pool, but you can create your own pools if you
+/// want to keep some kinds of work (e.g. CPU-bound and IO-bound jobs) from
+/// competing for the same threads.
+/// Dropping a pool shuts it down, but doesn't wait for its workers.
+pub struct WorkerPool {
+    name: Cow<'static, str>,
+    state: RwLock<PoolState>,
+}
 
-struct WorkerPool {
+struct PoolState {
     workers: Vec<Worker>,
-    sender: Option<Sender<Task>>,
+    queue: Option<Arc<TaskQueue>>,
+    /// The id the next spawned worker gets. Ids are never reused.
+    next_id: usize,
+    config: PoolConfig,
+    watchdog: Option<Arc<Watchdog>>,
+}
+
+impl PoolState {
+    /// Returns how many workers are still taking tasks, leaving out the ones
+    /// that have been retired.
+    fn live_workers(&self) -> usize {
+        return self
+            .workers
+            .iter()
+            .filter(|w| w.thread.is_some() && !w.retire.load(Ordering::Acquire))
+            .count();
+    }
+}
+
+/// How urgently a task should be run. Idle workers always pick up the most
+/// urgent task that is waiting, so `LOW` tasks only run when there's nothing
+/// `HIGH` or `NORMAL` left to do.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    HIGH = 0,
+    NORMAL = 1,
+    LOW = 2,
+}
+
+/// What `shutdown_with_timeout` should do with tasks that are still waiting
+/// in the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// Keep running queued tasks until the queue is empty or the deadline
+    /// passes.
+    DRAIN,
+    /// Throw away every queued task right away. Tasks that are already
+    /// running still get to finish.
+    REJECT,
+}
+
+/// What happened during `shutdown_with_timeout`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// How many tasks the pool finished running over its whole lifetime.
+    pub completed: usize,
+    /// How many queued tasks were thrown away without running.
+    pub abandoned: usize,
+    /// The ids of the workers that couldn't be joined, either because they
+    /// were still busy at the deadline or because their thread died.
+    pub stragglers: Vec<usize>,
 }
 
 struct Worker {
     id: usize,
+    /// Set when the pool is shrunk and this worker should stop.
+    retire: Arc<AtomicBool>,
+    counters: Arc<WorkerCounters>,
     thread: Option<std::thread::JoinHandle<()>>,
 }
 
-pub struct TaskHandle {
-    recv: Receiver<()>,
+/// Runs a thread start/stop hook, making sure a panic in it doesn't take the
+/// worker down with it.
+fn run_hook(hook: &Option<builder::ThreadHook>, id: usize, pool: &str) {
+    if let Some(hook) = hook {
+        if let Err(payload) = catch_unwind(AssertUnwindSafe(|| hook(id))) {
+            let message = panic_message(&payload);
+            serror!(
+                VANESSA_LOGGER,
+                "A thread hook of worker #{id} of pool '{pool}' panicked: {message}"
+            );
+        }
+    }
+}
+
+/// A handle to a task submitted with `bg`. It can be used to wait for the
+/// task to finish and to retrieve the value it returned.
+/// Handles can also be made by hand with `promise`.
+pub struct TaskHandle<T = ()> {
+    id: usize,
+    recv: Receiver<Result<T, TaskError>>,
+    hooks: Arc<Mutex<Hooks<T>>>,
+    token: CancelToken,
+    /// The queue of the pool the task was submitted to. Continuations get
+    /// submitted there too.
+    queue: Option<Arc<TaskQueue>>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// Whatever should happen when a task finishes, besides its result being
+/// sent to its handle.
+struct Hooks<T> {
+    finished: bool,
+    /// Whether the task finished successfully. Only meaningful once
+    /// `finished` is set.
+    succeeded: bool,
+    /// A continuation registered with `then`. If there is one, it gets the
+    /// result instead of the handle.
+    then: Option<Continuation<T>>,
+    /// Called with whether the task succeeded. These are what dependencies
+    /// and `select` hook into. Each one has an id so it can be removed again.
+    listeners: Vec<(usize, Listener)>,
+    next_listener: usize,
+}
+
+/// A continuation registered with `TaskHandle::then`.
+type Continuation<T> = Box<dyn FnOnce(Result<T, TaskError>) + Send + 'static>;
+
+/// Gets called with whether a task succeeded once it has finished.
+type Listener = Box<dyn FnOnce(bool) + Send + 'static>;
+
+/// Something a task can depend on. Get one from `TaskHandle::dependency`
+/// and pass it to `bg_when`.
+#[derive(Clone)]
+pub struct Dependency {
+    target: Arc<dyn Listen>,
+}
+
+/// Lets dependencies wait on handles without caring about their result type.
+trait Listen: Send + Sync {
+    /// Calls `f` with whether the task succeeded once it has finished, or
+    /// right away if it already has. Returns an id to pass to `unlisten` if
+    /// `f` is still waiting.
+    fn listen(&self, f: Listener) -> Option<usize>;
+
+    /// Removes a listener that hasn't been called yet.
+    fn unlisten(&self, id: usize);
+}
+
+impl<T: Send> Listen for Mutex<Hooks<T>> {
+    fn listen(&self, f: Listener) -> Option<usize> {
+        let mut hooks = match self.lock() {
+            Ok(hooks) => hooks,
+            Err(_) => {
+                f(false);
+                return None;
+            }
+        };
+        if hooks.finished {
+            let succeeded = hooks.succeeded;
+            drop(hooks);
+            f(succeeded);
+            return None;
+        }
+        let id = hooks.next_listener;
+        hooks.next_listener += 1;
+        hooks.listeners.push((id, f));
+        return Some(id);
+    }
+
+    fn unlisten(&self, id: usize) {
+        if let Ok(mut hooks) = self.lock() {
+            hooks.listeners.retain(|(i, _)| *i != id);
+        }
+    }
+}
+
+/// A token used to cancel a background task. Every `TaskHandle` carries one.
+/// Cancelling a task that hasn't started yet means it will never run.
+/// Tasks that are already running have to check `is_cancelled()` themselves
+/// and bail out early if they want to be cancellable.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    state: Arc<TokenState>,
+}
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    /// Called once when the token gets cancelled.
+    callbacks: Mutex<Vec<Box<dyn FnOnce() + Send + 'static>>>,
+}
+
+impl std::fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f
+            .debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish();
+    }
+}
+
+impl CancelToken {
+    /// Creates a new token that hasn't been cancelled.
+    pub fn new() -> CancelToken {
+        return CancelToken::default();
+    }
+
+    /// Cancels whatever this token is attached to.
+    pub fn cancel(&self) {
+        let callbacks = {
+            let mut callbacks = self
+                .state
+                .callbacks
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if self.state.cancelled.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            std::mem::take(&mut *callbacks)
+        };
+        for callback in callbacks {
+            callback();
+        }
+        // delayed and periodic tasks report back as soon as they're
+        // cancelled
+        timer::wake();
+    }
+
+    /// Returns whether this token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        return self.state.cancelled.load(Ordering::Acquire);
+    }
+
+    /// Calls `f` once the token gets cancelled, or right away if it already
+    /// has been.
+    fn on_cancel(&self, f: Box<dyn FnOnce() + Send + 'static>) {
+        let mut callbacks = self
+            .state
+            .callbacks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if self.is_cancelled() {
+            drop(callbacks);
+            f();
+            return;
+        }
+        callbacks.push(f);
+    }
+}
+
+thread_local! {
+    /// The token of the task currently running on this thread, if any.
+    static CURRENT_TOKEN: RefCell<Option<CancelToken>> = const { RefCell::new(None) };
+    /// The id of the task currently running on this thread, if any.
+    static CURRENT_TASK: Cell<Option<usize>> = const { Cell::new(None) };
+    /// The id of the worker running on this thread, if this is a worker.
+    static CURRENT_WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+    /// Whether tasks submitted from this thread are run right away instead
+    /// of being handed to a pool.
+    static INLINE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Turns inline mode on or off for the current thread. In inline mode `bg`
+/// and friends don't hand tasks to the pool, they run them right away on the
+/// calling thread before returning, so everything runs in submission order.
+/// No pool has to be initialized for this.
+/// This is meant for tests. It only affects the current thread, so tests
+/// running in parallel don't get in each other's way.
+/// Continuations and dependent tasks run inline too once they're ready,
+/// delayed tasks sleep on the calling thread first, and `bg_every` still
+/// needs a running pool.
+pub fn set_inline(inline: bool) {
+    INLINE.with(|i| i.set(inline));
+}
+
+/// Returns whether inline mode is on for the current thread. See
+/// `set_inline`.
+pub fn is_inline() -> bool {
+    return INLINE.with(|i| i.get());
+}
+
+/// Task ids are handed out from this. They are unique across all pools.
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the id of the worker the current thread belongs to, or `None` if
+/// this isn't a worker thread. Worker ids are only unique within their pool.
+pub fn current_worker_id() -> Option<usize> {
+    return CURRENT_WORKER.with(|w| w.get());
+}
+
+/// Returns the id of the background task running on the current thread, or
+/// `None` outside of a background task. See `TaskHandle::id`.
+pub fn current_task_id() -> Option<usize> {
+    return CURRENT_TASK.with(|t| t.get());
+}
+
+/// Returns whether the current thread is a worker of any pool. Blocking on
+/// a `require` from a worker ties it up, so this is handy for avoiding
+/// deadlocks.
+pub fn is_worker_thread() -> bool {
+    return current_worker_id().is_some();
+}
+
+/// Returns whether the task running on the current thread has been
+/// cancelled. Long-running tasks should check this every now and then.
+/// This always returns false outside of a background task.
+pub fn is_cancelled() -> bool {
+    return CURRENT_TOKEN.with(|token| match token.borrow().as_ref() {
+        Some(token) => token.is_cancelled(),
+        None => false,
+    });
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum TaskError {
     BROKENPOOL,
     POOLNOTINITIALIZED,
     BROKENCHANNEL,
+    /// The task panicked. Contains the panic message if there was one.
+    PANICKED(String),
+    /// The task was cancelled through its `CancelToken`.
+    CANCELLED,
+    /// The pool was shut down before the task could run.
+    SHUTDOWN,
+    /// One of the task's dependencies failed, so it was never run.
+    DEPENDENCYFAILED,
+    /// The task didn't finish in time.
+    TIMEDOUT,
+    /// The pool's queue is full. See `Builder::capacity`.
+    QUEUEFULL,
 }
 
-impl TaskHandle {
-    /// Waits until a task is done.
-    pub fn require(self) {
-        self.recv.recv().ok();
+impl<T> TaskHandle<T> {
+    /// Waits until a task is done and returns the value it produced.
+    /// If the task panicked this returns `TaskError::PANICKED`, and if it
+    /// never reports back at all this returns `TaskError::BROKENCHANNEL`.
+    pub fn require(self) -> Result<T, TaskError> {
+        let _blocked = watchdog::blocked();
+        match self.recv.recv() {
+            Ok(result) => result,
+            Err(_) => Err(TaskError::BROKENCHANNEL),
+        }
+    }
+
+    /// Checks whether the task is done without blocking. If it is, this
+    /// returns its result, otherwise it returns `None`.
+    /// Once this has returned `Some` the result has been taken and the
+    /// handle is spent.
+    pub fn try_require(&mut self) -> Option<Result<T, TaskError>> {
+        match self.recv.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(TaskError::BROKENCHANNEL)),
+        }
+    }
+
+    /// Waits up to `timeout` for the task to finish. Returns `None` if it
+    /// still hasn't finished by then.
+    /// Once this has returned `Some` the result has been taken and the
+    /// handle is spent.
+    pub fn require_timeout(&mut self, timeout: Duration) -> Option<Result<T, TaskError>> {
+        match self.recv.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Err(TaskError::BROKENCHANNEL)),
+        }
+    }
+
+    /// Returns whether the task has finished running. This never blocks and
+    /// does not take the result out of the handle.
+    pub fn is_done(&self) -> bool {
+        return match self.hooks.lock() {
+            Ok(hooks) => hooks.finished,
+            Err(_) => true,
+        };
+    }
+
+    /// Cancels the task. If it hasn't started yet it will be dropped without
+    /// running, and either way `require` will report `TaskError::CANCELLED`.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// Returns a clone of the task's cancellation token so it can be
+    /// cancelled from elsewhere.
+    pub fn cancel_token(&self) -> CancelToken {
+        return self.token.clone();
+    }
+
+    /// Returns the id of the task. Inside the task the same id is returned
+    /// by `current_task_id`.
+    pub fn id(&self) -> usize {
+        return self.id;
+    }
+
+    /// Returns how many listeners are waiting on the task.
+    #[cfg(test)]
+    pub(crate) fn listeners(&self) -> usize {
+        return match self.hooks.lock() {
+            Ok(hooks) => hooks.listeners.len(),
+            Err(_) => 0,
+        };
     }
 }
 
-type Task = Option<(Box<dyn FnOnce() + Send + 'static>, Sender<()>)>;
+impl<T: Send + 'static> TaskHandle<T> {
+    /// Runs `f` with the task's result once it's done, without tying up a
+    /// worker while waiting. The continuation is submitted to the same pool
+    /// as the task. If the task fails, `f` is never run and the returned
+    /// handle reports the same error.
+    pub fn then<F, U>(self, f: F) -> TaskHandle<U>
+    where
+        F: FnOnce(T) -> U + Send + 'static,
+        U: Send + 'static,
+    {
+        let (reply, mut handle) = reply();
+        let queue = match self.queue.clone() {
+            Some(queue) => Some(queue),
+            None if is_inline() => None,
+            None => GLOBAL_POOL.queue().ok(),
+        };
+        handle.queue = queue.clone();
+        let token = handle.cancel_token();
 
-static GLOBAL_POOL: RwLock<WorkerPool> = RwLock::new(WorkerPool {
-    sender: None,
-    workers: vec![],
-});
+        let continuation = move |result: Result<T, TaskError>| {
+            let value = match result {
+                Ok(value) => value,
+                Err(e) => {
+                    reply.send(Err(e));
+                    return;
+                }
+            };
+            if is_inline() {
+                wrap_task(move || f(value), reply, token)();
+                return;
+            }
+            let queue = match queue {
+                Some(queue) => queue,
+                None => {
+                    reply.send(Err(TaskError::POOLNOTINITIALIZED));
+                    return;
+                }
+            };
+            // if this fails the task gets dropped, which reports back
+            queue
+                .push(Priority::NORMAL, wrap_task(move || f(value), reply, token))
+                .ok();
+        };
 
-/// Initialize the workers subsystem with a specified number of threads.
-/// If you just want the maximum number, use `init()` instead.
-pub fn init_with(jobs: usize) {
-    let mut pool = match GLOBAL_POOL.write() {
-        Ok(pool) => pool,
-        Err(_) => {
-            serror!(
+        let mut hooks = match self.hooks.lock() {
+            Ok(hooks) => hooks,
+            Err(_) => {
+                continuation(Err(TaskError::BROKENCHANNEL));
+                return handle;
+            }
+        };
+        if hooks.finished {
+            drop(hooks);
+            let result = match self.recv.try_recv() {
+                Ok(result) => result,
+                Err(_) => Err(TaskError::BROKENCHANNEL),
+            };
+            continuation(result);
+        } else {
+            hooks.then = Some(Box::new(continuation));
+        }
+        return handle;
+    }
+
+    /// Returns a `Dependency` on this task, for use with `bg_when`.
+    pub fn dependency(&self) -> Dependency {
+        return Dependency {
+            target: self.hooks.clone(),
+        };
+    }
+}
+
+/// A scope for background tasks that are allowed to borrow from the stack.
+/// You get one from `scope` or `WorkerPool::scope`.
+pub struct Scope<'pool, 'env> {
+    pool: &'pool WorkerPool,
+    /// How many tasks of this scope haven't finished yet.
+    pending: Arc<(Mutex<usize>, Condvar)>,
+    // keeps 'env invariant so borrows can't be shortened behind our back.
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Marks a scoped task as finished when it's dropped, which happens whether
+/// the task ran, panicked, or got thrown away without running.
+struct ScopeGuard {
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let (count, cond) = &*self.pending;
+        // the count has to stay right even if the lock got poisoned, the
+        // scope relies on it to know when borrows are no longer in use.
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        *count -= 1;
+        cond.notify_all();
+    }
+}
+
+impl<'env> Scope<'_, 'env> {
+    /// Submit a background task that may borrow anything that outlives the
+    /// scope. Otherwise this works just like `bg`.
+    pub fn bg<F, T>(&self, f: F) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'env,
+        T: Send + 'static,
+    {
+        *self.pending.0.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        let guard = ScopeGuard {
+            pending: self.pending.clone(),
+        };
+        let task: Box<dyn FnOnce() -> T + Send + 'env> = Box::new(move || {
+            let _guard = guard;
+            f()
+        });
+        // SAFETY: the scope waits for every guard to be dropped before it
+        // returns, so whatever the task borrows outlives the task itself.
+        let task: Box<dyn FnOnce() -> T + Send + 'static> = unsafe { std::mem::transmute(task) };
+        return self.pool.bg(task);
+    }
+
+    /// Waits for every task of the scope to finish. This must never return
+    /// early, or tasks could outlive what they borrow.
+    fn wait(&self) {
+        let (count, cond) = &*self.pending;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count > 0 {
+            count = cond.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// A task as it sits in the queue. It returns false if it panicked.
+type Task = Box<dyn FnOnce() -> bool + Send + 'static>;
+
+/// Wraps `f` into a task that can be put on a queue, along with the handle
+/// that will receive its result.
+fn make_task<F, T>(f: F) -> (Task, TaskHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (reply, handle) = reply();
+    let task = wrap_task(f, reply, handle.cancel_token());
+    return (task, handle);
+}
+
+/// Wraps `f` into a task that sends its result to `reply`, and skips running
+/// `f` if `task_token` gets cancelled first.
+fn wrap_task<F, T>(f: F, reply: Reply<T>, task_token: CancelToken) -> Task
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let task = move || {
+        if task_token.is_cancelled() {
+            sdebug!(VANESSA_LOGGER, "Dropping a cancelled background task.");
+            reply.send(Err(TaskError::CANCELLED));
+            return true;
+        }
+
+        let previous = CURRENT_TOKEN.with(|t| t.replace(Some(task_token.clone())));
+        let previous_id = CURRENT_TASK.with(|t| t.replace(Some(reply.id)));
+        watchdog::task_started(reply.id);
+        // catching the panic here keeps the worker thread alive and lets us
+        // hand the message back to whoever is waiting on the task.
+        let result = match catch_unwind(AssertUnwindSafe(f)) {
+            Ok(_) if task_token.is_cancelled() => Err(TaskError::CANCELLED),
+            Ok(value) => Ok(value),
+            Err(payload) => {
+                let message = panic_message(&payload);
+                serror!(VANESSA_LOGGER, "A background task panicked: {message}");
+                Err(TaskError::PANICKED(message))
+            }
+        };
+        CURRENT_TOKEN.with(|t| t.replace(previous));
+        CURRENT_TASK.with(|t| t.set(previous_id));
+        watchdog::task_finished();
+
+        let succeeded = !matches!(result, Err(TaskError::PANICKED(_)));
+        reply.send(result);
+        return succeeded;
+    };
+
+    return Box::new(task);
+}
+
+/// The sending half of a task's result. If it gets dropped without sending
+/// anything (e.g. because its task was thrown away during a shutdown) it
+/// reports `TaskError::SHUTDOWN` instead, so nobody waits forever.
+struct Reply<T> {
+    id: usize,
+    send: Option<Sender<Result<T, TaskError>>>,
+    hooks: Arc<Mutex<Hooks<T>>>,
+}
+
+impl<T> Reply<T> {
+    fn send(mut self, result: Result<T, TaskError>) {
+        self.finish(result);
+    }
+
+    fn finish(&mut self, result: Result<T, TaskError>) {
+        let send = match self.send.take() {
+            Some(send) => send,
+            None => return,
+        };
+        let succeeded = result.is_ok();
+        let mut hooks = match self.hooks.lock() {
+            Ok(hooks) => hooks,
+            Err(_) => {
+                send.send(result).ok();
+                return;
+            }
+        };
+        hooks.finished = true;
+        hooks.succeeded = succeeded;
+        let then = hooks.then.take();
+        let listeners = std::mem::take(&mut hooks.listeners);
+        // the result is sent while still holding the lock so `then` can
+        // never see the task as finished without its result being there.
+        match then {
+            Some(then) => {
+                drop(hooks);
+                then(result);
+            }
+            None => {
+                send.send(result).ok();
+                drop(hooks);
+            }
+        }
+        for (_, listener) in listeners {
+            listener(succeeded);
+        }
+    }
+}
+
+impl<T> Drop for Reply<T> {
+    fn drop(&mut self) {
+        self.finish(Err(TaskError::SHUTDOWN));
+    }
+}
+
+/// Creates a new reply and the handle that receives it.
+fn reply<T>() -> (Reply<T>, TaskHandle<T>) {
+    let (send, recv) = channel();
+    let hooks = Arc::new(Mutex::new(Hooks {
+        finished: false,
+        succeeded: false,
+        then: None,
+        listeners: vec![],
+        next_listener: 0,
+    }));
+    let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+    let reply = Reply {
+        id,
+        send: Some(send),
+        hooks: hooks.clone(),
+    };
+    let handle = TaskHandle {
+        id,
+        recv,
+        hooks,
+        token: CancelToken::new(),
+        queue: None,
+    };
+    return (reply, handle);
+}
+
+/// Lets the rest of the crate submit tasks to a pool without having to hold
+/// on to the pool itself. It stops working once the pool is shut down.
+#[derive(Clone)]
+pub(crate) struct Spawner {
+    queue: Option<Arc<TaskQueue>>,
+}
+
+impl Spawner {
+    /// Runs `f` on the pool. In inline mode it runs right away instead.
+    pub(crate) fn bg<F>(&self, f: F) -> Result<(), TaskError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (task, _) = make_task(f);
+        if is_inline() {
+            task();
+            return Ok(());
+        }
+        return match &self.queue {
+            Some(queue) => queue.submit(Priority::NORMAL, task, true),
+            None => Err(TaskError::POOLNOTINITIALIZED),
+        };
+    }
+}
+
+static GLOBAL_POOL: WorkerPool = WorkerPool::empty(Cow::Borrowed("global"));
+
+impl WorkerPool {
+    const fn empty(name: Cow<'static, str>) -> WorkerPool {
+        return WorkerPool {
+            name,
+            state: RwLock::new(PoolState {
+                queue: None,
+                workers: vec![],
+                next_id: 0,
+                config: PoolConfig::new(),
+                watchdog: None,
+            }),
+        };
+    }
+
+    /// Creates a new worker pool with its own `jobs` threads. Use `Builder`
+    /// if you want to set up the threads some more.
+    pub fn new(name: &str, jobs: usize) -> WorkerPool {
+        return Builder::new().name(name).threads(jobs).build();
+    }
+
+    /// Sets up how workers spawned from now on are configured.
+    fn configure(&self, config: PoolConfig) {
+        match self.state.write() {
+            Ok(mut pool) => pool.config = config,
+            Err(_) => serror!(
+                VANESSA_LOGGER,
+                "Failed to lock pool '{}', cannot configure it.",
+                self.name
+            ),
+        }
+    }
+
+    /// Returns the name of this pool.
+    pub fn name(&self) -> &str {
+        return &self.name;
+    }
+
+    /// Starts this pool with `jobs` threads. Pools made with `new` are
+    /// already started, so this is mostly for bringing a pool back after it
+    /// was shut down. If the pool is already running it gets resized
+    /// instead.
+    pub fn start(&self, jobs: usize) {
+        let mut pool = match self.state.write() {
+            Ok(pool) => pool,
+            Err(_) => {
+                serror!(
+                    VANESSA_LOGGER,
+                    "Failed to lock pool '{}', cannot initialize background workers.",
+                    self.name
+                );
+                return;
+            }
+        };
+
+        if pool.queue.is_some() {
+            swarn!(
                 VANESSA_LOGGER,
-                "Failed to lock pool, cannot initialize background workers."
+                "Pool '{}' is already running, resizing it to {jobs} threads instead.",
+                self.name
             );
+            drop(pool);
+            self.resize(jobs);
             return;
         }
-    };
 
-    let (s, r) = std::sync::mpsc::channel();
+        // workers from before a non-blocking shutdown might still be
+        // draining the old queue. they'll exit on their own once that's done.
+        for w in pool.workers.drain(..) {
+            if w.thread.as_ref().is_some_and(|t| !t.is_finished()) {
+                sdebug!(
+                    VANESSA_LOGGER,
+                    "Leaving worker #{} of pool '{}' to finish its old work.",
+                    w.id,
+                    self.name
+                );
+            }
+        }
+
+        let queue = Arc::new(TaskQueue::new(pool.config.capacity));
+        pool.queue = Some(queue.clone());
+        pool.watchdog = pool
+            .config
+            .watchdog
+            .map(|threshold| Watchdog::start(self.name.clone(), threshold, queue.clone()));
+        for _ in 0..jobs {
+            self.spawn_worker(&mut pool, queue.clone());
+        }
+    }
+
+    /// Grows or shrinks the pool to `jobs` threads. Growing spawns new
+    /// workers right away. Shrinking asks some workers to stop once they're
+    /// done with their current task, and whatever is still queued is left
+    /// for the remaining workers. Nothing that was submitted gets lost, but
+    /// if you shrink to 0 it won't run until the pool grows again.
+    pub fn resize(&self, jobs: usize) {
+        let mut pool = match self.state.write() {
+            Ok(pool) => pool,
+            Err(_) => {
+                serror!(
+                    VANESSA_LOGGER,
+                    "Failed to lock pool '{}', cannot resize it.",
+                    self.name
+                );
+                return;
+            }
+        };
+        let queue = match pool.queue.clone() {
+            Some(queue) => queue,
+            None => {
+                serror!(
+                    VANESSA_LOGGER,
+                    "Attempted to resize the background worker pool '{}' before it has been initialized.",
+                    self.name
+                );
+                return;
+            }
+        };
 
-    pool.sender = Some(s);
+        // forget about workers that have already stopped
+        pool.workers
+            .retain(|w| w.thread.as_ref().is_some_and(|t| !t.is_finished()));
 
-    let r = Arc::new(Mutex::new(r));
+        let mut live: Vec<&Worker> = pool
+            .workers
+            .iter()
+            .filter(|w| !w.retire.load(Ordering::Acquire))
+            .collect();
+        sdebug!(
+            VANESSA_LOGGER,
+            "Resizing pool '{}' from {} to {jobs} threads.",
+            self.name,
+            live.len()
+        );
+        if live.len() > jobs {
+            for w in live.split_off(jobs) {
+                w.retire.store(true, Ordering::Release);
+            }
+            queue.wake_all();
+        } else {
+            for _ in live.len()..jobs {
+                self.spawn_worker(&mut pool, queue.clone());
+            }
+        }
+    }
 
-    for i in 0..jobs {
-        let r = r.clone();
+    fn spawn_worker(&self, pool: &mut PoolState, queue: Arc<TaskQueue>) {
+        let i = pool.next_id;
+        pool.next_id += 1;
+        let name = self.name.to_string();
+        let retire = Arc::new(AtomicBool::new(false));
+        let worker_retire = retire.clone();
+        let counters = Arc::new(WorkerCounters::default());
+        let worker_counters = counters.clone();
+        let config = pool.config.clone();
+        let activity = pool.watchdog.as_ref().map(|w| w.watch(i));
 
-        pool.workers.push(Worker {
-            id: i,
-            thread: Some(std::thread::spawn(move || {
-                sdebug!(VANESSA_LOGGER, "Initializing background worker #{i}");
+        let prefix = config.thread_name.as_deref().unwrap_or(&self.name);
+        let mut builder = std::thread::Builder::new().name(format!("{prefix}-{i}"));
+        if let Some(stack_size) = config.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let thread = builder.spawn(move || {
+            sdebug!(
+                VANESSA_LOGGER,
+                "Initializing background worker #{i} of pool '{name}'"
+            );
+            CURRENT_WORKER.with(|w| w.set(Some(i)));
+            if let Some(activity) = activity {
+                watchdog::enter(activity);
+            }
+            run_hook(&config.on_thread_start, i, &name);
 
-                loop {
-                    let task: Task = r.lock().unwrap().recv().unwrap();
-                    match task {
-                        Some((task, chn)) => {
-                            sdebug!(VANESSA_LOGGER, "Background worker #{i} got a task!");
-                            task();
-                            chn.send(()).ok();
-                        }
-                        None => {
-                            sdebug!(
+            // working out a log timestamp takes longer than running a small
+            // task, so only bother if someone is going to see the message.
+            let log_tasks = VANESSA_LOGGER.tlevel == LogLevel::HYPER
+                || VANESSA_LOGGER.flevel == LogLevel::HYPER;
+            let local = queue.register();
+            loop {
+                match queue.pop(&worker_retire, &local) {
+                    Some(queued) => {
+                        if log_tasks {
+                            shyper!(
                                 VANESSA_LOGGER,
-                                "Background worker #{i} received shutdown signal!"
+                                "Background worker #{i} of pool '{name}' got a task!"
                             );
-                            break;
                         }
+                        queue.run(queued, &worker_counters);
+                    }
+                    None => {
+                        sdebug!(
+                            VANESSA_LOGGER,
+                            "Background worker #{i} of pool '{name}' received shutdown signal!"
+                        );
+                        break;
                     }
                 }
-            })),
+            }
+            queue.unregister(&local);
+            run_hook(&config.on_thread_stop, i, &name);
         });
+
+        match thread {
+            Ok(thread) => pool.workers.push(Worker {
+                id: i,
+                retire,
+                counters,
+                thread: Some(thread),
+            }),
+            Err(e) => serror!(
+                VANESSA_LOGGER,
+                "Failed to spawn worker #{i} of pool '{}': {e}",
+                self.name
+            ),
+        }
     }
-}
 
-/// Initializes the worker subsystem with the default number of threads.
-/// If we can detect a core count, it will use all of the available cores.
-/// Otherwise it defaults to 1.
-pub fn init() {
-    let avail = match std::thread::available_parallelism() {
-        Ok(v) => v,
-        Err(_) => NonZeroUsize::new(1).unwrap(),
-    };
-    sdebug!(
-        VANESSA_LOGGER,
-        "Initializing background tasks subsystem with {} threads",
-        avail
-    );
-    init_with(avail.into());
-}
+    /// Submit a background task to this pool. It will be executed by one of
+    /// the pool's threads as soon as one is available. Whatever the closure
+    /// returns can be retrieved from the returned handle with `require`.
+    /// If the pool has a capacity and its queue is full, this waits until
+    /// there's room.
+    pub fn bg<F, T>(&self, f: F) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        return self.bg_with_priority(Priority::NORMAL, f);
+    }
 
-/// Submit a background task. It will be executed by a thread on the worker
-/// pool as soon as one is available.
-pub fn bg<F>(f: F) -> Result<TaskHandle, TaskError>
-where
-    F: FnOnce() + Send + 'static,
-{
-    let pool = match GLOBAL_POOL.read() {
-        Ok(pool) => pool,
-        Err(_) => {
-            serror!(VANESSA_LOGGER, "Failed to submit a background task!");
-            return Err(TaskError::BROKENPOOL);
+    /// Same as `bg`, but lets you pick how urgently the task should be run.
+    pub fn bg_with_priority<F, T>(
+        &self,
+        priority: Priority,
+        f: F,
+    ) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        return self.submit(priority, f, true);
+    }
+
+    /// Same as `bg`, but if the pool's queue is full it fails right away with
+    /// `TaskError::QUEUEFULL` instead of waiting for room. See
+    /// `Builder::capacity`.
+    pub fn try_bg<F, T>(&self, f: F) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        return self.submit(Priority::NORMAL, f, false);
+    }
+
+    fn submit<F, T>(
+        &self,
+        priority: Priority,
+        f: F,
+        block: bool,
+    ) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if is_inline() {
+            let (task, handle) = make_task(f);
+            task();
+            return Ok(handle);
         }
-    };
-    if pool.sender.is_none() {
-        serror!(
-            VANESSA_LOGGER,
-            "Tried to submit a background task before subsystem initialized!"
-        );
-        return Err(TaskError::POOLNOTINITIALIZED);
+        let queue = self.queue()?;
+        let (task, mut handle) = make_task(f);
+        handle.queue = Some(queue.clone());
+        match queue.submit(priority, task, block) {
+            Ok(_) => {
+                return Ok(handle);
+            }
+            Err(TaskError::QUEUEFULL) => {
+                return Err(TaskError::QUEUEFULL);
+            }
+            Err(e) => {
+                serror!(VANESSA_LOGGER, "Failed to submit a background task!");
+                return Err(e);
+            }
+        };
     }
 
-    let (send, recv) = channel();
+    /// Submit a background task that will only be run once `delay` has
+    /// passed. Cancelling the returned handle before then means the task
+    /// never runs.
+    pub fn bg_after<F, T>(&self, delay: Duration, f: F) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if is_inline() {
+            std::thread::sleep(delay);
+            return self.bg(f);
+        }
+        let queue = self.queue()?;
+        let (task, mut handle) = make_task(f);
+        handle.queue = Some(queue.clone());
+        let token = handle.cancel_token();
+        let due = epoch_millis() + delay.as_millis();
+        timer::schedule(
+            due,
+            token.clone(),
+            Box::new(move || {
+                // cancelled tasks just report back, no need to bother the pool
+                if token.is_cancelled() {
+                    task();
+                    return;
+                }
+                if queue.push(Priority::NORMAL, task).is_err() {
+                    swarn!(VANESSA_LOGGER, "Failed to submit a delayed task!");
+                }
+            }),
+        )?;
+        return Ok(handle);
+    }
 
-    match pool
-        .sender
-        .as_ref()
-        .unwrap()
-        .send(Some((Box::new(f), send)))
+    /// Runs `f` on this pool every `period`, starting one `period` from now,
+    /// until the returned handle is cancelled. If a run is still going when
+    /// the next one is due, that next one is skipped.
+    /// Requiring the handle waits until the task is cancelled or the pool
+    /// goes away.
+    pub fn bg_every<F>(&self, period: Duration, f: F) -> Result<TaskHandle<()>, TaskError>
+    where
+        F: Fn() + Send + Sync + 'static,
     {
-        Ok(_) => {
-            return Ok(TaskHandle { recv });
+        let queue = self.queue()?;
+        return timer::periodic(queue, period, Box::new(f));
+    }
+
+    /// Submit a background task that only runs once every one of `deps` has
+    /// finished. Dependencies can be chained into whole graphs of tasks, and
+    /// nothing waits on a worker while its dependencies are still running.
+    /// If any dependency fails the task is never run, and its handle reports
+    /// `TaskError::DEPENDENCYFAILED`.
+    pub fn bg_when<F, T>(&self, deps: &[Dependency], f: F) -> Result<TaskHandle<T>, TaskError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if deps.is_empty() {
+            return self.bg(f);
+        }
+        let queue = match is_inline() {
+            // there might not be a pool at all, which is fine as long as the
+            // dependencies finish on an inline thread too.
+            true => self.state.read().ok().and_then(|pool| pool.queue.clone()),
+            false => Some(self.queue()?),
+        };
+        let (reply, mut handle) = reply();
+        handle.queue = queue.clone();
+        let token = handle.cancel_token();
+
+        let waiting = Arc::new(Mutex::new(Some((f, reply))));
+        let remaining = Arc::new(AtomicUsize::new(deps.len()));
+        let failed = Arc::new(AtomicBool::new(false));
+        for dep in deps {
+            let waiting = waiting.clone();
+            let remaining = remaining.clone();
+            let failed = failed.clone();
+            let queue = queue.clone();
+            let token = token.clone();
+            dep.target.listen(Box::new(move |succeeded| {
+                if !succeeded {
+                    failed.store(true, Ordering::Release);
+                }
+                if remaining.fetch_sub(1, Ordering::AcqRel) != 1 {
+                    return;
+                }
+                let (f, reply) = match waiting.lock() {
+                    Ok(mut waiting) => match waiting.take() {
+                        Some(waiting) => waiting,
+                        None => return,
+                    },
+                    Err(_) => return,
+                };
+                if failed.load(Ordering::Acquire) {
+                    reply.send(Err(TaskError::DEPENDENCYFAILED));
+                    return;
+                }
+                let task = wrap_task(f, reply, token);
+                if is_inline() {
+                    task();
+                    return;
+                }
+                // if this fails the task gets dropped, which reports back
+                match queue {
+                    Some(queue) => queue.push(Priority::NORMAL, task).ok(),
+                    None => None,
+                };
+            }));
         }
-        Err(_) => {
-            serror!(VANESSA_LOGGER, "Failed to submit a background task!");
-            return Err(TaskError::BROKENCHANNEL);
+        return Ok(handle);
+    }
+
+    /// Returns a `Spawner` for this pool, or an error if it isn't running.
+    /// In inline mode there doesn't have to be a running pool.
+    pub(crate) fn spawner(&self) -> Result<Spawner, TaskError> {
+        if is_inline() {
+            let queue = self.state.read().ok().and_then(|pool| pool.queue.clone());
+            return Ok(Spawner { queue });
         }
-    };
-}
+        return Ok(Spawner {
+            queue: Some(self.queue()?),
+        });
+    }
 
-/// Shuts down the workers subsystem. Don't call this if you intend to use
-/// workers at any future point in your program.
-pub fn shutdown() {
-    let pool = match GLOBAL_POOL.read() {
-        Ok(pool) => pool,
-        Err(_) => {
-            serror!(VANESSA_LOGGER, "Failed to shutdown worker pool!");
+    /// Returns the queue of this pool, or an error if it isn't running.
+    fn queue(&self) -> Result<Arc<TaskQueue>, TaskError> {
+        let pool = match self.state.read() {
+            Ok(pool) => pool,
+            Err(_) => {
+                serror!(VANESSA_LOGGER, "Failed to submit a background task!");
+                return Err(TaskError::BROKENPOOL);
+            }
+        };
+        return match pool.queue.as_ref() {
+            Some(queue) => Ok(queue.clone()),
+            None => {
+                serror!(
+                    VANESSA_LOGGER,
+                    "Tried to submit a background task to pool '{}' before it was initialized!",
+                    self.name
+                );
+                Err(TaskError::POOLNOTINITIALIZED)
+            }
+        };
+    }
+
+    /// Shuts down this pool. Work that was already submitted will still be
+    /// run, but nothing new can be submitted afterwards.
+    pub fn shutdown(&self) {
+        let mut pool = match self.state.write() {
+            Ok(pool) => pool,
+            Err(_) => {
+                serror!(
+                    VANESSA_LOGGER,
+                    "Failed to shutdown worker pool '{}'!",
+                    self.name
+                );
+                return;
+            }
+        };
+        if pool.queue.is_none() {
+            serror!(
+                VANESSA_LOGGER,
+                "Attempted to shutdown the background worker pool '{}' before it has been initialized.",
+                self.name
+            );
             return;
         }
-    };
-    if pool.sender.is_none() {
-        serror!(
-            VANESSA_LOGGER,
-            "Attempted to shutdown the background worker pool before it has been initialized."
-        );
-        return;
+
+        // every worker takes exactly one signal, and only once the queue has
+        // been drained. retired workers stop without one.
+        let workers = pool.live_workers();
+        let queue = pool.queue.take().unwrap();
+        drop(pool);
+        if queue.signal_shutdown(workers).is_err() {
+            swarn!(
+                VANESSA_LOGGER,
+                "Failed to send shutdown signals to the workers of pool '{}'",
+                self.name
+            );
+            swarn!(VANESSA_LOGGER, "They will not be stopped.");
+        }
+        // with nobody left to drain the queue, whatever is in it is thrown
+        // away so the handles hear back.
+        if workers == 0 {
+            queue.clear();
+        }
+    }
+
+    /// Shuts down this pool and blocks until all of its workers have
+    /// concluded their work.
+    pub fn shutdown_blocking(&self) {
+        self.shutdown();
+
+        // the lock can't be held while joining, the tasks the workers are
+        // still draining might need it (for `stats` or `bg`, say).
+        let workers: Vec<(usize, std::thread::JoinHandle<()>)> = match self.state.write() {
+            Ok(mut pool) => pool
+                .workers
+                .iter_mut()
+                .filter_map(|w| Some((w.id, w.thread.take()?)))
+                .collect(),
+            Err(_) => {
+                serror!(
+                    VANESSA_LOGGER,
+                    "Failed to shutdown worker pool '{}'!",
+                    self.name
+                );
+                return;
+            }
+        };
+
+        for (id, thread) in workers {
+            match thread.join() {
+                Ok(_) => {
+                    sdebug!(VANESSA_LOGGER, "Thread of worker #{id} joined.");
+                }
+                Err(_) => {
+                    swarn!(
+                        VANESSA_LOGGER,
+                        "Failed to join the thread of worker #{id}. It might get accidentally killed in a main-thread exit!"
+                    );
+                }
+            };
+        }
     }
 
-    for w in &pool.workers {
-        // this doesnt guarantee that specific worker will get the signal
-        if pool.sender.as_ref().unwrap().send(None).is_err() {
+    /// Shuts down this pool and waits up to `timeout` for its workers to
+    /// finish. What happens to tasks that are still queued depends on the
+    /// `policy`. Any task still queued once the deadline passes is thrown
+    /// away, and workers that are still busy by then are left to finish on
+    /// their own and listed in the report.
+    /// Tasks that get thrown away report `TaskError::SHUTDOWN` to their
+    /// handles.
+    pub fn shutdown_with_timeout(
+        &self,
+        timeout: Duration,
+        policy: ShutdownPolicy,
+    ) -> ShutdownReport {
+        let deadline = epoch_millis() + timeout.as_millis();
+        let mut report = ShutdownReport {
+            completed: 0,
+            abandoned: 0,
+            stragglers: vec![],
+        };
+
+        let (queue, live, mut workers) = {
+            let mut pool = match self.state.write() {
+                Ok(pool) => pool,
+                Err(_) => {
+                    serror!(
+                        VANESSA_LOGGER,
+                        "Failed to shutdown worker pool '{}'!",
+                        self.name
+                    );
+                    return report;
+                }
+            };
+            let queue = match pool.queue.take() {
+                Some(queue) => queue,
+                None => {
+                    serror!(
+                        VANESSA_LOGGER,
+                        "Attempted to shutdown the background worker pool '{}' before it has been initialized.",
+                        self.name
+                    );
+                    return report;
+                }
+            };
+            let live = pool.live_workers();
+            let workers: Vec<(usize, std::thread::JoinHandle<()>)> = pool
+                .workers
+                .iter_mut()
+                .filter_map(|w| Some((w.id, w.thread.take()?)))
+                .collect();
+            (queue, live, workers)
+        };
+
+        // retired workers stop without a signal
+        if queue.signal_shutdown(live).is_err() {
             swarn!(
                 VANESSA_LOGGER,
-                "Failed to send shutdown signal for worker #{}",
-                w.id
+                "Failed to send shutdown signals to the workers of pool '{}'",
+                self.name
             );
-            swarn!(VANESSA_LOGGER, "It will not be stopped.");
+        }
+        if policy == ShutdownPolicy::REJECT {
+            report.abandoned += queue.clear();
+        }
+
+        while epoch_millis() < deadline && workers.iter().any(|(_, t)| !t.is_finished()) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // whatever didn't make it in time isn't going to run
+        report.abandoned += queue.clear();
+
+        for (id, thread) in workers.drain(..) {
+            if !thread.is_finished() {
+                swarn!(
+                    VANESSA_LOGGER,
+                    "Worker #{id} of pool '{}' didn't finish in time, leaving it behind.",
+                    self.name
+                );
+                report.stragglers.push(id);
+                continue;
+            }
+            match thread.join() {
+                Ok(_) => {
+                    sdebug!(VANESSA_LOGGER, "Thread of worker #{id} joined.");
+                }
+                Err(_) => {
+                    swarn!(VANESSA_LOGGER, "Failed to join the thread of worker #{id}.");
+                    report.stragglers.push(id);
+                }
+            }
+        }
+
+        report.completed = queue.completed.load(Ordering::Relaxed);
+        return report;
+    }
+
+    /// Returns how many slow tasks the watchdog has warned about and how
+    /// many times it has reported the pool as deadlocked.
+    #[cfg(test)]
+    pub(crate) fn watchdog_reports(&self) -> (usize, usize) {
+        return match self.state.read() {
+            Ok(pool) => pool.watchdog.as_ref().map_or((0, 0), |w| w.reports()),
+            Err(_) => (0, 0),
+        };
+    }
+
+    /// Returns a snapshot of what this pool is up to.
+    pub fn stats(&self) -> PoolStats {
+        let mut stats = PoolStats {
+            name: self.name.to_string(),
+            queued: 0,
+            running: 0,
+            completed: 0,
+            failed: 0,
+            workers: vec![],
+            latency: LatencyStats::default(),
+        };
+        let pool = match self.state.read() {
+            Ok(pool) => pool,
+            Err(_) => return stats,
+        };
+        let live: Vec<&Worker> = pool
+            .workers
+            .iter()
+            .filter(|w| w.thread.is_some() && !w.retire.load(Ordering::Acquire))
+            .collect();
+        stats.workers = live.iter().map(|w| w.counters.snapshot(w.id)).collect();
+        let mut latencies = vec![];
+        for w in &live {
+            w.counters.collect_latencies(&mut latencies);
+        }
+        stats.latency = stats::percentiles(latencies);
+        if let Some(queue) = pool.queue.as_ref() {
+            stats.queued = queue.len();
+            stats.running = queue.running.load(Ordering::Relaxed);
+            stats.completed = queue.completed.load(Ordering::Relaxed);
+            stats.failed = queue.failed.load(Ordering::Relaxed);
+        }
+        return stats;
+    }
+
+    /// Returns how many worker threads this pool has.
+    pub fn threads(&self) -> usize {
+        return match self.state.read() {
+            Ok(pool) if pool.queue.is_some() => pool.live_workers(),
+            _ => 0,
+        };
+    }
+
+    /// Applies `f` to every item on the pool and returns the results in the
+    /// same order as the input. The items are split into one chunk per
+    /// worker thread.
+    /// Don't call this from inside a task on the same pool, it blocks until
+    /// every chunk is done.
+    pub fn par_map<I, F, T, U>(&self, items: I, f: F) -> Result<Vec<U>, TaskError>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(T) -> U + Send + Sync + 'static,
+        T: Send + 'static,
+        U: Send + 'static,
+    {
+        let items: Vec<T> = items.into_iter().collect();
+        let chunk_size = items.len().div_ceil(self.threads().max(1));
+        let f = Arc::new(f);
+        let results = self.par_chunks(items, chunk_size, move |chunk| {
+            chunk.into_iter().map(|item| f(item)).collect::<Vec<U>>()
+        })?;
+        return Ok(results.into_iter().flatten().collect());
+    }
+
+    /// Runs `f` on every item on the pool and waits for all of them to be
+    /// done. The items are split into one chunk per worker thread.
+    /// Don't call this from inside a task on the same pool, it blocks until
+    /// every chunk is done.
+    pub fn par_for_each<I, F, T>(&self, items: I, f: F) -> Result<(), TaskError>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(T) + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        self.par_map(items, f)?;
+        return Ok(());
+    }
+
+    /// Splits the items into chunks of `chunk_size` and runs `f` on each
+    /// chunk on the pool. Returns one result per chunk, in input order.
+    /// Don't call this from inside a task on the same pool, it blocks until
+    /// every chunk is done.
+    pub fn par_chunks<I, F, T, U>(
+        &self,
+        items: I,
+        chunk_size: usize,
+        f: F,
+    ) -> Result<Vec<U>, TaskError>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(Vec<T>) -> U + Send + Sync + 'static,
+        T: Send + 'static,
+        U: Send + 'static,
+    {
+        let chunk_size = chunk_size.max(1);
+        let f = Arc::new(f);
+        let mut handles = vec![];
+        let mut items = items.into_iter().peekable();
+        while items.peek().is_some() {
+            let chunk: Vec<T> = items.by_ref().take(chunk_size).collect();
+            let f = f.clone();
+            handles.push(self.bg(move || f(chunk))?);
+        }
+
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(handle.require()?);
+        }
+        return Ok(results);
+    }
+
+    /// Runs `f` with a `Scope` that can submit tasks borrowing from the
+    /// stack. Every task submitted through the scope is guaranteed to be
+    /// done by the time this returns, even if `f` panics.
+    /// Don't call this from inside a task on the same pool, it blocks until
+    /// every scoped task is done.
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'_, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            pending: Arc::new((Mutex::new(0), Condvar::new())),
+            _env: PhantomData,
+        };
+        let result = catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+        return match result {
+            Ok(result) => result,
+            Err(payload) => resume_unwind(payload),
+        };
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        let running = match self.state.read() {
+            Ok(pool) => pool.queue.is_some(),
+            Err(_) => false,
+        };
+        if running {
+            self.shutdown();
         }
     }
 }
 
-/// Shuts down the workers subsystem. Don't call this if you intend to use
-/// workers at any future point in your program.
+/// Returns the global worker pool. This is the pool used by `bg` and the
+/// other free functions in this module.
+pub fn global() -> &'static WorkerPool {
+    return &GLOBAL_POOL;
+}
+
+/// Initialize the workers subsystem with a specified number of threads.
+/// If you just want the maximum number, use `init()` instead.
+/// Calling this while the workers are already running resizes the pool.
+/// This resets anything set up through `Builder`.
+pub fn init_with(jobs: usize) {
+    Builder::new().threads(jobs).init();
+}
+
+/// Initializes the worker subsystem with the default number of threads.
+/// If we can detect a core count, it will use all of the available cores.
+/// Otherwise it defaults to 1.
+/// Use `Builder` if you want to set up the threads some more.
+pub fn init() {
+    Builder::new().init();
+}
+
+/// Grows or shrinks the global pool to `jobs` threads without losing any
+/// queued work. See `WorkerPool::resize`.
+pub fn resize(jobs: usize) {
+    GLOBAL_POOL.resize(jobs);
+}
+
+/// Returns a snapshot of what the global pool is up to.
+pub fn stats() -> PoolStats {
+    return GLOBAL_POOL.stats();
+}
+
+/// Logs a summary of `stats()` through Vanessa's logger every `period`,
+/// until the returned handle is cancelled.
+pub fn log_stats_every(period: Duration) -> Result<TaskHandle<()>, TaskError> {
+    return GLOBAL_POOL.bg_every(period, || {
+        sinfo!(VANESSA_LOGGER, "{}", GLOBAL_POOL.stats());
+    });
+}
+
+/// Submit a background task. It will be executed by a thread on the worker
+/// pool as soon as one is available. Whatever the closure returns can be
+/// retrieved from the returned handle with `require`.
+pub fn bg<F, T>(f: F) -> Result<TaskHandle<T>, TaskError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    return GLOBAL_POOL.bg(f);
+}
+
+/// Same as `bg`, but lets you pick how urgently the task should be run.
+/// Plain `bg` uses `Priority::NORMAL`.
+pub fn bg_with_priority<F, T>(priority: Priority, f: F) -> Result<TaskHandle<T>, TaskError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    return GLOBAL_POOL.bg_with_priority(priority, f);
+}
+
+/// Same as `bg`, but fails with `TaskError::QUEUEFULL` instead of waiting if
+/// the global pool's queue is full. See `WorkerPool::try_bg`.
+pub fn try_bg<F, T>(f: F) -> Result<TaskHandle<T>, TaskError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    return GLOBAL_POOL.try_bg(f);
+}
+
+/// Submit a background task to the global pool that will only be run once
+/// `delay` has passed. See `WorkerPool::bg_after`.
+pub fn bg_after<F, T>(delay: Duration, f: F) -> Result<TaskHandle<T>, TaskError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    return GLOBAL_POOL.bg_after(delay, f);
+}
+
+/// Submit a background task to the global pool that only runs once every one
+/// of `deps` has finished. See `WorkerPool::bg_when`.
+pub fn bg_when<F, T>(deps: &[Dependency], f: F) -> Result<TaskHandle<T>, TaskError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    return GLOBAL_POOL.bg_when(deps, f);
+}
+
+/// Runs `f` on the global pool every `period` until the returned handle is
+/// cancelled. See `WorkerPool::bg_every`.
+pub fn bg_every<F>(period: Duration, f: F) -> Result<TaskHandle<()>, TaskError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    return GLOBAL_POOL.bg_every(period, f);
+}
+
+/// Shuts down the workers subsystem. It can be brought back afterwards with
+/// `init` or `init_with`.
+pub fn shutdown() {
+    GLOBAL_POOL.shutdown();
+}
+
+/// Shuts down the workers subsystem, waiting at most `timeout` for the
+/// workers to finish. See `WorkerPool::shutdown_with_timeout`.
+pub fn shutdown_with_timeout(timeout: Duration, policy: ShutdownPolicy) -> ShutdownReport {
+    return GLOBAL_POOL.shutdown_with_timeout(timeout, policy);
+}
+
+/// Shuts down the workers subsystem. It can be brought back afterwards with
+/// `init` or `init_with`.
 /// This variant blocks until all of the background workers have concluded
 /// their work. Useful if your main thread doesn't do anything while everything
 /// is processed on background workers.
 pub fn shutdown_blocking() {
-    shutdown();
+    GLOBAL_POOL.shutdown_blocking();
+}
 
-    let mut pool = match GLOBAL_POOL.write() {
-        Ok(pool) => pool,
-        Err(_) => {
-            serror!(VANESSA_LOGGER, "Failed to shutdown worker pool!");
-            return;
+/// Applies `f` to every item on the global pool and returns the results in
+/// the same order as the input. See `WorkerPool::par_map`.
+pub fn par_map<I, F, T, U>(items: I, f: F) -> Result<Vec<U>, TaskError>
+where
+    I: IntoIterator<Item = T>,
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    return GLOBAL_POOL.par_map(items, f);
+}
+
+/// Runs `f` on every item on the global pool and waits for all of them to be
+/// done. See `WorkerPool::par_for_each`.
+pub fn par_for_each<I, F, T>(items: I, f: F) -> Result<(), TaskError>
+where
+    I: IntoIterator<Item = T>,
+    F: Fn(T) + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    return GLOBAL_POOL.par_for_each(items, f);
+}
+
+/// Splits the items into chunks of `chunk_size` and runs `f` on each chunk on
+/// the global pool. See `WorkerPool::par_chunks`.
+pub fn par_chunks<I, F, T, U>(items: I, chunk_size: usize, f: F) -> Result<Vec<U>, TaskError>
+where
+    I: IntoIterator<Item = T>,
+    F: Fn(Vec<T>) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    return GLOBAL_POOL.par_chunks(items, chunk_size, f);
+}
+
+/// Runs `f` with a `Scope` on the global pool. Tasks submitted through the
+/// scope can borrow from the stack, and they are all guaranteed to be done by
+/// the time this returns. See `WorkerPool::scope`.
+pub fn scope<'env, F, R>(f: F) -> R
+where
+    F: FnOnce(&Scope<'_, 'env>) -> R,
+{
+    return GLOBAL_POOL.scope(f);
+}
+
+/// Waits for every task in `handles` to finish and returns their results in
+/// the same order.
+pub fn join_all<T>(handles: Vec<TaskHandle<T>>) -> Vec<Result<T, TaskError>> {
+    return handles.into_iter().map(|h| h.require()).collect();
+}
+
+/// Same as `join_all`, but waits at most `timeout` overall. Tasks that
+/// haven't finished by then get `TaskError::TIMEDOUT` as their result.
+pub fn join_all_timeout<T>(
+    handles: Vec<TaskHandle<T>>,
+    timeout: Duration,
+) -> Vec<Result<T, TaskError>> {
+    let deadline = epoch_millis() + timeout.as_millis();
+    let mut results = Vec::with_capacity(handles.len());
+    for mut handle in handles {
+        let left = deadline.saturating_sub(epoch_millis());
+        let result = match handle.require_timeout(Duration::from_millis(left as u64)) {
+            Some(result) => result,
+            None => Err(TaskError::TIMEDOUT),
+        };
+        results.push(result);
+    }
+    return results;
+}
+
+/// Waits for whichever task in `handles` finishes first, takes its handle out
+/// of the list and returns its index along with its result. The other
+/// handles stay where they were, so this can be called in a loop to handle
+/// tasks in the order they finish. Returns `None` if `handles` is empty.
+pub fn select<T: Send + 'static>(
+    handles: &mut Vec<TaskHandle<T>>,
+) -> Option<(usize, Result<T, TaskError>)> {
+    return select_inner(handles, None);
+}
+
+/// Same as `select`, but gives up and returns `None` if none of the tasks
+/// finish within `timeout`.
+pub fn select_timeout<T: Send + 'static>(
+    handles: &mut Vec<TaskHandle<T>>,
+    timeout: Duration,
+) -> Option<(usize, Result<T, TaskError>)> {
+    return select_inner(handles, Some(timeout));
+}
+
+fn select_inner<T: Send + 'static>(
+    handles: &mut Vec<TaskHandle<T>>,
+    timeout: Option<Duration>,
+) -> Option<(usize, Result<T, TaskError>)> {
+    if handles.is_empty() {
+        return None;
+    }
+    // every handle tells us when it's done
+    let (send, recv) = channel();
+    let listeners: Vec<Option<usize>> = handles
+        .iter()
+        .enumerate()
+        .map(|(i, handle)| {
+            let send = send.clone();
+            return handle.hooks.listen(Box::new(move |_| {
+                send.send(i).ok();
+            }));
+        })
+        .collect();
+    drop(send);
+
+    let ready = match timeout {
+        Some(timeout) => recv.recv_timeout(timeout).ok(),
+        None => {
+            let _blocked = watchdog::blocked();
+            recv.recv().ok()
         }
     };
-
-    for w in &mut pool.workers {
-        if w.thread.is_none() {
-            continue;
+    // the handles that didn't win might be selected on again and again, so
+    // their listeners can't be left piling up.
+    for (handle, id) in handles.iter().zip(listeners) {
+        if let Some(id) = id {
+            handle.hooks.unlisten(id);
         }
-        match w.thread.take().unwrap().join() {
-            Ok(_) => {
-                sdebug!(VANESSA_LOGGER, "Thread of worker #{} joined.", w.id);
-            }
-            Err(_) => {
-                swarn!(VANESSA_LOGGER, "Failed to join a thread. It might get accidentally killed in a main-thread exit!");
-                return;
-            }
-        };
     }
+    let i = ready?;
+    let handle = handles.remove(i);
+    return Some((i, handle.require()));
 }
@@ -0,0 +1,165 @@
+use std::{borrow::Cow, num::NonZeroUsize, sync::Arc, time::Duration};
+
+use crate::{log::VANESSA_LOGGER, sdebug};
+
+use super::{WorkerPool, GLOBAL_POOL};
+
+/// Called on a worker thread with the id of the worker.
+pub(super) type ThreadHook = Arc<dyn Fn(usize) + Send + Sync + 'static>;
+
+/// How the threads of a pool get set up.
+#[derive(Clone)]
+pub(super) struct PoolConfig {
+    /// Worker threads are named `{thread_name}-{id}`. Defaults to the name
+    /// of the pool.
+    pub(super) thread_name: Option<String>,
+    pub(super) stack_size: Option<usize>,
+    pub(super) on_thread_start: Option<ThreadHook>,
+    pub(super) on_thread_stop: Option<ThreadHook>,
+    /// How many tasks can be waiting in the queue. Unbounded if `None`.
+    pub(super) capacity: Option<usize>,
+    /// Tasks running for longer than this get warned about. No watchdog is
+    /// started if `None`.
+    pub(super) watchdog: Option<Duration>,
+}
+
+impl PoolConfig {
+    pub(super) const fn new() -> PoolConfig {
+        return PoolConfig {
+            thread_name: None,
+            stack_size: None,
+            on_thread_start: None,
+            on_thread_stop: None,
+            capacity: None,
+            watchdog: None,
+        };
+    }
+}
+
+/// Sets up a worker pool. Use `build` to get a new pool, or `init` to
+/// (re)initialize the global one.
+#[derive(Clone)]
+pub struct Builder {
+    name: String,
+    threads: Option<usize>,
+    config: PoolConfig,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        return Builder::new();
+    }
+}
+
+impl Builder {
+    /// Creates a builder for a pool called "pool" with default settings.
+    pub fn new() -> Builder {
+        return Builder {
+            name: "pool".into(),
+            threads: None,
+            config: PoolConfig::new(),
+        };
+    }
+
+    /// Sets the name of the pool. This is ignored by `init`, the global pool
+    /// is always called "global".
+    pub fn name(mut self, name: &str) -> Builder {
+        self.name = name.into();
+        return self;
+    }
+
+    /// Sets how many threads the pool gets. Defaults to the number of
+    /// available cores, or 1 if that can't be detected.
+    pub fn threads(mut self, threads: usize) -> Builder {
+        self.threads = Some(threads);
+        return self;
+    }
+
+    /// Worker threads get named `{prefix}-{id}`, which shows up in panic
+    /// messages and debuggers. Defaults to the name of the pool.
+    pub fn thread_name(mut self, prefix: &str) -> Builder {
+        self.config.thread_name = Some(prefix.into());
+        return self;
+    }
+
+    /// Sets the stack size of the worker threads in bytes. Defaults to
+    /// whatever `std::thread` uses.
+    pub fn stack_size(mut self, bytes: usize) -> Builder {
+        self.config.stack_size = Some(bytes);
+        return self;
+    }
+
+    /// Runs `f` on every worker thread when it starts, before it picks up
+    /// any tasks. It gets the id of the worker.
+    pub fn on_thread_start<F>(mut self, f: F) -> Builder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.config.on_thread_start = Some(Arc::new(f));
+        return self;
+    }
+
+    /// Runs `f` on every worker thread right before it stops, be it because
+    /// of a shutdown or because the pool was shrunk. It gets the id of the
+    /// worker.
+    pub fn on_thread_stop<F>(mut self, f: F) -> Builder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.config.on_thread_stop = Some(Arc::new(f));
+        return self;
+    }
+
+    /// Limits how many tasks can be waiting in the pool's queue. Once it's
+    /// full, `bg` blocks until there's room again and `try_bg` fails with
+    /// `TaskError::QUEUEFULL`. Tasks submitted from the pool's own workers
+    /// are always let through, so they can't deadlock the pool.
+    /// The queue is unbounded by default.
+    pub fn capacity(mut self, capacity: usize) -> Builder {
+        self.config.capacity = Some(capacity.max(1));
+        return self;
+    }
+
+    /// Starts a watchdog thread alongside the pool. It logs a warning for
+    /// every task that has been running for longer than `threshold`, and an
+    /// error if the pool deadlocks because every worker is stuck in
+    /// `require` waiting on tasks that are still queued.
+    pub fn watchdog(mut self, threshold: Duration) -> Builder {
+        self.config.watchdog = Some(threshold);
+        return self;
+    }
+
+    fn thread_count(&self) -> usize {
+        if let Some(threads) = self.threads {
+            return threads;
+        }
+        let avail = match std::thread::available_parallelism() {
+            Ok(v) => v,
+            Err(_) => NonZeroUsize::new(1).unwrap(),
+        };
+        return avail.into();
+    }
+
+    /// Creates and starts a new pool.
+    pub fn build(self) -> WorkerPool {
+        let threads = self.thread_count();
+        let pool = WorkerPool::empty(Cow::Owned(self.name));
+        pool.configure(self.config);
+        pool.start(threads);
+        return pool;
+    }
+
+    /// Initializes the global pool with this configuration. If the global
+    /// pool is already running it gets resized, and only workers spawned
+    /// from then on use the new configuration.
+    pub fn init(self) {
+        let threads = self.thread_count();
+        sdebug!(
+            VANESSA_LOGGER,
+            "Initializing background tasks subsystem with {} threads",
+            threads
+        );
+        GLOBAL_POOL.configure(self.config);
+        GLOBAL_POOL.start(threads);
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+
+use super::{reply, CancelToken, Reply, TaskError, TaskHandle};
+
+/// A value that some other piece of code promised to provide later. This is
+/// just a `TaskHandle`, so it can be required, cancelled, chained with
+/// `then`, depended on with `bg_when` and waited on with `join_all` or
+/// `select` like any task. Continuations of a promise run on the global pool.
+/// Cancelling a promise settles it with `TaskError::CANCELLED` right away,
+/// whatever the completer does afterwards.
+pub type Promise<T = ()> = TaskHandle<T>;
+
+/// The other half of a `Promise`. Whoever holds it provides the value, from
+/// whatever thread they like. If it gets dropped without doing so, the
+/// promise gets `TaskError::BROKENCHANNEL`.
+pub struct Completer<T = ()> {
+    /// Shared with the promise's cancel token, which settles the promise
+    /// when it gets cancelled. Whoever gets here first takes the reply.
+    reply: Arc<Mutex<Option<Reply<T>>>>,
+    token: CancelToken,
+}
+
+/// Creates a connected `Completer` and `Promise`.
+pub fn promise<T: Send + 'static>() -> (Completer<T>, Promise<T>) {
+    let (reply, handle) = reply();
+    let reply = Arc::new(Mutex::new(Some(reply)));
+    let token = handle.cancel_token();
+    let cancelled = Arc::downgrade(&reply);
+    token.on_cancel(Box::new(move || {
+        if let Some(reply) = cancelled.upgrade() {
+            settle(&reply, Err(TaskError::CANCELLED));
+        }
+    }));
+    return (Completer { reply, token }, handle);
+}
+
+/// Sends `result` to the promise, unless it has been settled already.
+fn settle<T>(reply: &Mutex<Option<Reply<T>>>, result: Result<T, TaskError>) {
+    // taken out first so continuations don't run while holding the lock
+    let taken = reply.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(reply) = taken {
+        reply.send(result);
+    }
+}
+
+impl<T> Completer<T> {
+    /// Fulfils the promise with `value`. Does nothing if the promise was
+    /// cancelled.
+    pub fn complete(self, value: T) {
+        settle(&self.reply, Ok(value));
+    }
+
+    /// Fails the promise with `error`. Does nothing if the promise was
+    /// cancelled.
+    pub fn fail(self, error: TaskError) {
+        settle(&self.reply, Err(error));
+    }
+
+    /// Returns whether the promise was cancelled. Nobody is interested in
+    /// the value anymore, so producing it can be skipped.
+    pub fn is_cancelled(&self) -> bool {
+        return self.token.is_cancelled();
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        // does nothing if the promise was already settled
+        settle(&self.reply, Err(TaskError::BROKENCHANNEL));
+    }
+}
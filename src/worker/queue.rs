@@ -0,0 +1,481 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    time::Instant,
+};
+
+use crate::channel::{self, Receiver, Sender};
+
+use super::{stats::WorkerCounters, Priority, Task, TaskError};
+
+/// The most tasks a worker moves from the injector into its own deque in one
+/// go. Grabbing a few at a time means the injector lock gets taken less.
+const MAX_BATCH: usize = 16;
+
+/// A task waiting to be run, along with when it was submitted.
+pub(super) struct Queued {
+    task: Task,
+    queued_at: Instant,
+}
+
+/// The deque belonging to a single worker. Its owner takes tasks off the
+/// front, other workers steal from the back.
+pub(super) struct LocalQueue {
+    tasks: Mutex<VecDeque<Queued>>,
+    /// How many tasks are in `tasks`, so empty deques can be skipped without
+    /// locking them. Only changed while holding the lock.
+    len: AtomicUsize,
+}
+
+impl LocalQueue {
+    fn push(&self, queued: Queued) {
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.push_back(queued);
+            self.len.store(tasks.len(), Ordering::Release);
+        }
+    }
+
+    fn extend(&self, batch: impl Iterator<Item = Queued>) {
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.extend(batch);
+            self.len.store(tasks.len(), Ordering::Release);
+        }
+    }
+
+    /// Takes a task off the front, or the back if `steal` is set.
+    fn pop(&self, steal: bool) -> Option<Queued> {
+        if self.len.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let mut tasks = self.tasks.lock().ok()?;
+        let queued = match steal {
+            true => tasks.pop_back(),
+            false => tasks.pop_front(),
+        };
+        self.len.store(tasks.len(), Ordering::Release);
+        return queued;
+    }
+
+    fn drain(&self) -> VecDeque<Queued> {
+        return match self.tasks.lock() {
+            Ok(mut tasks) => {
+                self.len.store(0, Ordering::Release);
+                std::mem::take(&mut *tasks)
+            }
+            Err(_) => VecDeque::new(),
+        };
+    }
+}
+
+thread_local! {
+    /// The deque of the worker running on this thread, along with the id of
+    /// the queue it belongs to. Tasks submitted from inside a worker go
+    /// straight into its own deque.
+    static LOCAL: RefCell<Option<(usize, Arc<LocalQueue>)>> = const { RefCell::new(None) };
+}
+
+/// One priority level of the injector.
+struct Lane {
+    send: Sender<Queued>,
+    recv: Receiver<Queued>,
+    /// How many tasks are in the lane. Bumped before a task is sent and
+    /// dropped after one is taken, so it never undercounts, and an empty
+    /// lane can be skipped without taking the channel's lock.
+    len: AtomicUsize,
+}
+
+impl Lane {
+    fn new() -> Lane {
+        let (send, recv) = channel::unbounded();
+        return Lane {
+            send,
+            recv,
+            len: AtomicUsize::new(0),
+        };
+    }
+
+    fn send(&self, queued: Queued) -> Result<(), TaskError> {
+        self.len.fetch_add(1, Ordering::SeqCst);
+        if self.send.send(queued).is_err() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+            return Err(TaskError::BROKENCHANNEL);
+        }
+        return Ok(());
+    }
+
+    fn try_recv(&self) -> Option<Queued> {
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let queued = self.recv.try_recv().ok()?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        return Some(queued);
+    }
+}
+
+struct SleepState {
+    /// How many workers still have to pick up a shutdown signal.
+    shutdown_signals: usize,
+}
+
+/// The scheduler of a pool. Every worker has its own deque and steals from
+/// the others when it runs dry. Tasks submitted from outside the pool (and
+/// tasks that aren't `NORMAL` priority) go through a shared injector with one
+/// lane per priority level. Each lane is a `vanessa::channel`, so any worker
+/// can take from it.
+/// Workers look for work in this order: high priority injector lane, their
+/// own deque, normal priority injector lane, other workers' deques, low
+/// priority injector lane. Only normal priority tasks ever end up in a
+/// worker's deque, so priorities are still respected.
+pub(super) struct TaskQueue {
+    injector: [Lane; 3],
+    locals: RwLock<Vec<Arc<LocalQueue>>>,
+    /// How many deques are in `locals`, so it doesn't have to be locked
+    /// just to find out.
+    workers: AtomicUsize,
+    /// How many tasks are waiting anywhere in the queue. This is bumped
+    /// before a task is added and dropped after it's taken, so it never
+    /// undercounts.
+    pending: AtomicUsize,
+    /// How many workers are (about to be) asleep.
+    sleepers: AtomicUsize,
+    /// Used to spread out where workers start stealing from.
+    steal_cursor: AtomicUsize,
+    /// Set once the pool starts shutting down. Nothing new can be pushed
+    /// after that.
+    closed: AtomicBool,
+    sleep: Mutex<SleepState>,
+    wake: Condvar,
+    /// The most tasks that can be waiting in the queue before `submit`
+    /// pushes back, if there's a limit.
+    capacity: Option<usize>,
+    /// Held while checking for and taking up room in a bounded queue.
+    room: Mutex<()>,
+    /// Signalled whenever room frees up in a bounded queue.
+    room_freed: Condvar,
+    /// How many tasks are being run right now.
+    pub(super) running: AtomicUsize,
+    /// How many tasks the workers have finished running without panicking.
+    pub(super) completed: AtomicUsize,
+    /// How many tasks panicked.
+    pub(super) failed: AtomicUsize,
+}
+
+impl TaskQueue {
+    pub(super) fn new(capacity: Option<usize>) -> TaskQueue {
+        return TaskQueue {
+            injector: [Lane::new(), Lane::new(), Lane::new()],
+            locals: RwLock::new(vec![]),
+            workers: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            steal_cursor: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(SleepState {
+                shutdown_signals: 0,
+            }),
+            wake: Condvar::new(),
+            capacity,
+            room: Mutex::new(()),
+            room_freed: Condvar::new(),
+            running: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        };
+    }
+
+    /// Identifies this queue so workers know whether a task is being
+    /// submitted to their own pool.
+    fn id(&self) -> usize {
+        return self as *const TaskQueue as usize;
+    }
+
+    /// Whether the current thread is one of this queue's workers.
+    fn is_own_worker(&self) -> bool {
+        return LOCAL.with(|local| {
+            local
+                .borrow()
+                .as_ref()
+                .is_some_and(|(id, _)| *id == self.id())
+        });
+    }
+
+    /// Submits a task while respecting the capacity of the queue. If the
+    /// queue is full this either waits for room, or fails with
+    /// `TaskError::QUEUEFULL` if `block` is false.
+    /// The pool's own workers never wait here, since if all of them did
+    /// nobody would be left to make room.
+    pub(super) fn submit(
+        &self,
+        priority: Priority,
+        task: Task,
+        block: bool,
+    ) -> Result<(), TaskError> {
+        let capacity = match self.capacity {
+            Some(capacity) if !self.is_own_worker() => capacity,
+            _ => return self.push(priority, task),
+        };
+        let mut room = match self.room.lock() {
+            Ok(room) => room,
+            Err(_) => return Err(TaskError::BROKENCHANNEL),
+        };
+        while self.pending.load(Ordering::SeqCst) >= capacity {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(TaskError::SHUTDOWN);
+            }
+            if !block {
+                return Err(TaskError::QUEUEFULL);
+            }
+            room = match self.room_freed.wait(room) {
+                Ok(room) => room,
+                Err(_) => return Err(TaskError::BROKENCHANNEL),
+            };
+        }
+        // keep holding the lock until the task is counted, so nobody else
+        // can take the same spot.
+        let result = self.push(priority, task);
+        drop(room);
+        return result;
+    }
+
+    /// Tells whoever is waiting in `submit` that some room freed up.
+    fn free_room(&self, all: bool) {
+        if self.capacity.is_none() {
+            return;
+        }
+        // taking the lock makes sure a submitter that just saw a full queue
+        // is actually waiting before we notify it.
+        let _room = self.room.lock();
+        if all {
+            self.room_freed.notify_all();
+        } else {
+            self.room_freed.notify_one();
+        }
+    }
+
+    /// Pushes a task into the queue no matter how full it is. This is what
+    /// the pool uses for continuations and timers, which must never block.
+    pub(super) fn push(&self, priority: Priority, task: Task) -> Result<(), TaskError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(TaskError::SHUTDOWN);
+        }
+        let mut queued = Some(Queued {
+            task,
+            queued_at: Instant::now(),
+        });
+        self.pending.fetch_add(1, Ordering::SeqCst);
+
+        if priority == Priority::NORMAL {
+            LOCAL.with(|local| {
+                if let Some((id, local)) = local.borrow().as_ref() {
+                    if *id == self.id() {
+                        local.push(queued.take().unwrap());
+                    }
+                }
+            });
+        }
+        if let Some(queued) = queued {
+            if let Err(e) = self.injector[priority as usize].send(queued) {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            // taking the lock makes sure the sleeper is actually waiting
+            // before we notify it.
+            let _sleep = self.sleep.lock();
+            self.wake.notify_one();
+        }
+        return Ok(());
+    }
+
+    /// Sets up a deque for a new worker on the current thread.
+    pub(super) fn register(&self) -> Arc<LocalQueue> {
+        let local = Arc::new(LocalQueue {
+            tasks: Mutex::new(VecDeque::new()),
+            len: AtomicUsize::new(0),
+        });
+        if let Ok(mut locals) = self.locals.write() {
+            locals.push(local.clone());
+            self.workers.store(locals.len(), Ordering::Relaxed);
+        }
+        LOCAL.with(|l| l.replace(Some((self.id(), local.clone()))));
+        return local;
+    }
+
+    /// Removes a worker's deque. Anything still in it goes back to the
+    /// injector so the other workers can pick it up.
+    pub(super) fn unregister(&self, local: &Arc<LocalQueue>) {
+        LOCAL.with(|l| l.replace(None));
+        if let Ok(mut locals) = self.locals.write() {
+            locals.retain(|l| !Arc::ptr_eq(l, local));
+            self.workers.store(locals.len(), Ordering::Relaxed);
+        }
+        let leftovers = local.drain();
+        if leftovers.is_empty() {
+            return;
+        }
+        for queued in leftovers {
+            self.injector[Priority::NORMAL as usize].send(queued).ok();
+        }
+        self.wake_all();
+    }
+
+    /// Tells `workers` workers to stop once there is no work left.
+    pub(super) fn signal_shutdown(&self, workers: usize) -> Result<(), TaskError> {
+        self.closed.store(true, Ordering::Release);
+        let mut sleep = match self.sleep.lock() {
+            Ok(sleep) => sleep,
+            Err(_) => return Err(TaskError::BROKENCHANNEL),
+        };
+        sleep.shutdown_signals += workers;
+        self.wake.notify_all();
+        drop(sleep);
+        // anyone waiting for room would wait forever otherwise
+        self.free_room(true);
+        return Ok(());
+    }
+
+    /// Blocks until there is a task to run. Returns `None` if the worker
+    /// should shut down (or has been retired) instead.
+    pub(super) fn pop(&self, retire: &AtomicBool, local: &LocalQueue) -> Option<Queued> {
+        loop {
+            if retire.load(Ordering::Acquire) {
+                return None;
+            }
+            if let Some(queued) = self.find(local) {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                self.free_room(false);
+                return Some(queued);
+            }
+
+            let mut sleep = self.sleep.lock().ok()?;
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            if self.pending.load(Ordering::SeqCst) > 0 {
+                // something is on its way in, go look again
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                drop(sleep);
+                std::thread::yield_now();
+                continue;
+            }
+            if retire.load(Ordering::Acquire) {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+            if sleep.shutdown_signals > 0 {
+                sleep.shutdown_signals -= 1;
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+            sleep = self.wake.wait(sleep).ok()?;
+            drop(sleep);
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn find(&self, local: &LocalQueue) -> Option<Queued> {
+        if let Some(queued) = self.take_injected(Priority::HIGH, None) {
+            return Some(queued);
+        }
+        if let Some(queued) = local.pop(false) {
+            return Some(queued);
+        }
+        if let Some(queued) = self.take_injected(Priority::NORMAL, Some(local)) {
+            return Some(queued);
+        }
+        if let Some(queued) = self.steal(local) {
+            return Some(queued);
+        }
+        return self.take_injected(Priority::LOW, None);
+    }
+
+    /// Takes a task out of one of the injector lanes. If `local` is given,
+    /// a fair share of whatever else is in the lane gets moved over to it.
+    fn take_injected(&self, priority: Priority, local: Option<&LocalQueue>) -> Option<Queued> {
+        let lane = &self.injector[priority as usize];
+        let queued = lane.try_recv()?;
+        if let Some(local) = local {
+            let workers = self.workers.load(Ordering::Relaxed).max(1);
+            let batch = (lane.len.load(Ordering::SeqCst) / workers).min(MAX_BATCH);
+            if batch > 0 {
+                local.extend(std::iter::from_fn(|| lane.try_recv()).take(batch));
+            }
+        }
+        return Some(queued);
+    }
+
+    fn steal(&self, local: &LocalQueue) -> Option<Queued> {
+        // nobody to steal from
+        if self.workers.load(Ordering::Relaxed) < 2 {
+            return None;
+        }
+        let locals = self.locals.read().ok()?;
+        let start = self.steal_cursor.fetch_add(1, Ordering::Relaxed);
+        for i in 0..locals.len() {
+            let victim = &locals[(start + i) % locals.len()];
+            if std::ptr::eq(Arc::as_ptr(victim), local) {
+                continue;
+            }
+            if let Some(queued) = victim.pop(true) {
+                return Some(queued);
+            }
+        }
+        return None;
+    }
+
+    /// Returns whether the pool has started shutting down.
+    pub(super) fn is_closed(&self) -> bool {
+        return self.closed.load(Ordering::Acquire);
+    }
+
+    /// Returns how many tasks are waiting in the queue.
+    pub(super) fn len(&self) -> usize {
+        return self.pending.load(Ordering::SeqCst);
+    }
+
+    /// Runs a task that was popped off the queue and keeps count of it.
+    pub(super) fn run(&self, queued: Queued, counters: &WorkerCounters) {
+        self.running.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let succeeded = (queued.task)();
+        counters.record(started.elapsed(), queued.queued_at.elapsed());
+        self.running.fetch_sub(1, Ordering::Relaxed);
+        if succeeded {
+            self.completed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wakes every sleeping worker so they can check if they were retired.
+    pub(super) fn wake_all(&self) {
+        // taking the lock makes sure nobody is between checking their
+        // retire flag and going to sleep.
+        let _sleep = self.sleep.lock();
+        self.wake.notify_all();
+    }
+
+    /// Throws away every task that is still waiting in the queue and returns
+    /// how many there were.
+    pub(super) fn clear(&self) -> usize {
+        let mut dropped: Vec<Queued> = vec![];
+        for lane in &self.injector {
+            dropped.extend(std::iter::from_fn(|| lane.try_recv()));
+        }
+        if let Ok(locals) = self.locals.read() {
+            for local in locals.iter() {
+                dropped.extend(local.drain());
+            }
+        }
+        self.pending.fetch_sub(dropped.len(), Ordering::SeqCst);
+        self.free_room(true);
+        // the tasks get dropped here, outside of the locks, since dropping
+        // them reports back to their handles.
+        return dropped.len();
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// How many of the most recent task latencies each worker keeps around for
+/// working out percentiles.
+const LATENCY_SAMPLES: usize = 1024;
+
+/// A snapshot of what a worker pool is up to. Get one with `stats` or
+/// `WorkerPool::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The name of the pool.
+    pub name: String,
+    /// How many tasks are waiting to be picked up.
+    pub queued: usize,
+    /// How many tasks are being run right now.
+    pub running: usize,
+    /// How many tasks have finished without panicking.
+    pub completed: usize,
+    /// How many tasks have panicked.
+    pub failed: usize,
+    /// Per-worker numbers, one entry for every live worker.
+    pub workers: Vec<WorkerStats>,
+    /// How long recent tasks took from being submitted to being finished.
+    pub latency: LatencyStats,
+}
+
+/// Numbers for a single worker thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// The id of the worker.
+    pub id: usize,
+    /// How many tasks this worker has run.
+    pub tasks: usize,
+    /// How much time this worker has spent running tasks.
+    pub busy: Duration,
+}
+
+/// Latency percentiles over the most recent tasks. Everything is zero if no
+/// task has finished yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencyStats {
+    /// Half the tasks were done within this long of being queued.
+    pub p50: Duration,
+    /// 90% of the tasks were done within this long of being queued.
+    pub p90: Duration,
+    /// 99% of the tasks were done within this long of being queued.
+    pub p99: Duration,
+    /// The longest any task took from being queued to being done.
+    pub max: Duration,
+}
+
+impl Display for PoolStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Pool '{}': {} queued, {} running, {} completed, {} failed, latency p50 {:?} p90 {:?} p99 {:?} max {:?}",
+            self.name,
+            self.queued,
+            self.running,
+            self.completed,
+            self.failed,
+            self.latency.p50,
+            self.latency.p90,
+            self.latency.p99,
+            self.latency.max
+        )?;
+        for w in &self.workers {
+            write!(f, ", #{} busy {:?} over {} tasks", w.id, w.busy, w.tasks)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Counters kept by every worker for itself. Keeping these per worker means
+/// workers never fight over them.
+#[derive(Default)]
+pub(super) struct WorkerCounters {
+    tasks: AtomicUsize,
+    busy_nanos: AtomicU64,
+    /// The most recent task latencies.
+    latencies: Mutex<VecDeque<Duration>>,
+}
+
+impl WorkerCounters {
+    pub(super) fn record(&self, busy: Duration, latency: Duration) {
+        self.tasks.fetch_add(1, Ordering::Relaxed);
+        self.busy_nanos
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+        if let Ok(mut latencies) = self.latencies.lock() {
+            if latencies.len() == LATENCY_SAMPLES {
+                latencies.pop_front();
+            }
+            latencies.push_back(latency);
+        }
+    }
+
+    pub(super) fn snapshot(&self, id: usize) -> WorkerStats {
+        return WorkerStats {
+            id,
+            tasks: self.tasks.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+        };
+    }
+
+    /// Adds this worker's recent latencies to `samples`.
+    pub(super) fn collect_latencies(&self, samples: &mut Vec<Duration>) {
+        if let Ok(latencies) = self.latencies.lock() {
+            samples.extend(latencies.iter());
+        }
+    }
+}
+
+/// Works out latency percentiles from a bunch of samples.
+pub(super) fn percentiles(mut samples: Vec<Duration>) -> LatencyStats {
+    if samples.is_empty() {
+        return LatencyStats::default();
+    }
+    samples.sort();
+    let at = |p: usize| samples[(samples.len() - 1) * p / 100];
+    return LatencyStats {
+        p50: at(50),
+        p90: at(90),
+        p99: at(99),
+        max: samples[samples.len() - 1],
+    };
+}
@@ -0,0 +1,216 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{log::VANESSA_LOGGER, sdebug, serror, time::epoch_millis};
+
+use super::{
+    panic_message, reply, watchdog, CancelToken, Priority, Reply, TaskError, TaskHandle, TaskQueue,
+    CURRENT_TASK, CURRENT_TOKEN,
+};
+
+/// Something that should happen at (or after) a certain point in time.
+struct TimerEntry {
+    /// When this entry is due, in UNIX epoch milliseconds. It fires once
+    /// this millisecond has fully passed, never before.
+    due: u128,
+    /// Entries whose token gets cancelled are fired early so whoever is
+    /// waiting on them finds out right away.
+    token: CancelToken,
+    fire: Box<dyn FnOnce() + Send + 'static>,
+}
+
+struct TimerState {
+    entries: Vec<TimerEntry>,
+    running: bool,
+}
+
+static TIMER: Mutex<TimerState> = Mutex::new(TimerState {
+    entries: vec![],
+    running: false,
+});
+static TIMER_WAKE: Condvar = Condvar::new();
+
+/// Schedules `fire` to be called on the timer thread once `due` has passed.
+/// The timer thread is started the first time this is called.
+pub(super) fn schedule(
+    due: u128,
+    token: CancelToken,
+    fire: Box<dyn FnOnce() + Send + 'static>,
+) -> Result<(), TaskError> {
+    let mut timer = match TIMER.lock() {
+        Ok(timer) => timer,
+        Err(_) => {
+            serror!(
+                VANESSA_LOGGER,
+                "Failed to lock the timer, cannot schedule a task."
+            );
+            return Err(TaskError::BROKENPOOL);
+        }
+    };
+    if !timer.running {
+        sdebug!(VANESSA_LOGGER, "Starting the timer thread.");
+        let spawned = std::thread::Builder::new()
+            .name("vanessa-timer".to_string())
+            .spawn(run);
+        if let Err(e) = spawned {
+            serror!(VANESSA_LOGGER, "Failed to start the timer thread: {e}");
+            return Err(TaskError::BROKENPOOL);
+        }
+        timer.running = true;
+    }
+    timer.entries.push(TimerEntry { due, token, fire });
+    TIMER_WAKE.notify_one();
+    return Ok(());
+}
+
+/// Wakes the timer thread up so it notices a cancelled entry right away.
+pub(super) fn wake() {
+    // taking the lock makes sure the timer thread is either waiting already
+    // or hasn't checked the tokens yet, so the wakeup can't get lost.
+    if let Ok(timer) = TIMER.lock() {
+        if timer.running && !timer.entries.is_empty() {
+            TIMER_WAKE.notify_one();
+        }
+    }
+}
+
+fn run() {
+    let mut timer = match TIMER.lock() {
+        Ok(timer) => timer,
+        Err(_) => return,
+    };
+    loop {
+        let now = epoch_millis();
+        let ready = timer
+            .entries
+            .iter()
+            // epoch_millis rounds down, so an entry is only due once the
+            // clock is past its millisecond.
+            .position(|e| e.due < now || e.token.is_cancelled());
+        if let Some(i) = ready {
+            let entry = timer.entries.swap_remove(i);
+            // don't hold the lock while firing, it might schedule more
+            drop(timer);
+            (entry.fire)();
+            timer = match TIMER.lock() {
+                Ok(timer) => timer,
+                Err(_) => return,
+            };
+            continue;
+        }
+
+        // sleep until the next entry is due, or until something gets
+        // scheduled or cancelled. with no entries there's no need to wake up
+        // on our own at all.
+        let next = timer.entries.iter().map(|e| e.due).min();
+        timer = match next {
+            Some(due) => {
+                let wait = Duration::from_millis((due + 1 - now) as u64);
+                match TIMER_WAKE.wait_timeout(timer, wait) {
+                    Ok((timer, _)) => timer,
+                    Err(_) => return,
+                }
+            }
+            None => match TIMER_WAKE.wait(timer) {
+                Ok(timer) => timer,
+                Err(_) => return,
+            },
+        };
+    }
+}
+
+/// The shared state of a task started with `bg_every`.
+struct Periodic {
+    queue: Arc<TaskQueue>,
+    period: u128,
+    f: Box<dyn Fn() + Send + Sync + 'static>,
+    /// Every run counts as the same task.
+    id: usize,
+    token: CancelToken,
+    reply: Mutex<Option<Reply<()>>>,
+    /// Whether a run is still in progress. Ticks that come up while this is
+    /// set are skipped.
+    running: AtomicBool,
+}
+
+impl Periodic {
+    /// Reports back to the handle. This only does anything the first time.
+    fn finish(&self, result: Result<(), TaskError>) {
+        if let Ok(mut reply) = self.reply.lock() {
+            if let Some(reply) = reply.take() {
+                reply.send(result);
+            }
+        }
+    }
+}
+
+/// Starts running `f` on `queue` every `period`.
+pub(super) fn periodic(
+    queue: Arc<TaskQueue>,
+    period: Duration,
+    f: Box<dyn Fn() + Send + Sync + 'static>,
+) -> Result<TaskHandle<()>, TaskError> {
+    let (reply, mut handle) = reply();
+    handle.queue = Some(queue.clone());
+    let periodic = Arc::new(Periodic {
+        queue,
+        period: period.as_millis().max(1),
+        f,
+        id: handle.id(),
+        token: handle.cancel_token(),
+        reply: Mutex::new(Some(reply)),
+        running: AtomicBool::new(false),
+    });
+    let due = epoch_millis() + periodic.period;
+    schedule_tick(periodic, due)?;
+    return Ok(handle);
+}
+
+fn schedule_tick(periodic: Arc<Periodic>, due: u128) -> Result<(), TaskError> {
+    let token = periodic.token.clone();
+    return schedule(due, token, Box::new(move || tick(periodic, due)));
+}
+
+fn tick(periodic: Arc<Periodic>, due: u128) {
+    if periodic.token.is_cancelled() {
+        periodic.finish(Err(TaskError::CANCELLED));
+        return;
+    }
+
+    if !periodic.running.swap(true, Ordering::AcqRel) {
+        let run = periodic.clone();
+        let task = Box::new(move || {
+            let previous = CURRENT_TOKEN.with(|t| t.replace(Some(run.token.clone())));
+            let previous_id = CURRENT_TASK.with(|t| t.replace(Some(run.id)));
+            watchdog::task_started(run.id);
+            let result = catch_unwind(AssertUnwindSafe(|| (run.f)()));
+            if let Err(payload) = &result {
+                let message = panic_message(payload);
+                serror!(
+                    VANESSA_LOGGER,
+                    "A periodic background task panicked: {message}"
+                );
+            }
+            CURRENT_TOKEN.with(|t| t.replace(previous));
+            CURRENT_TASK.with(|t| t.set(previous_id));
+            watchdog::task_finished();
+            run.running.store(false, Ordering::Release);
+            return result.is_ok();
+        });
+        if let Err(e) = periodic.queue.push(Priority::NORMAL, task) {
+            periodic.finish(Err(e));
+            return;
+        }
+    }
+
+    let next = due + periodic.period;
+    if let Err(e) = schedule_tick(periodic.clone(), next) {
+        periodic.finish(Err(e));
+    }
+}
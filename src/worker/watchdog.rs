@@ -0,0 +1,218 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{log::VANESSA_LOGGER, sdebug, serror, swarn};
+
+use super::TaskQueue;
+
+/// What a worker is up to, as far as the watchdog is concerned.
+#[derive(Default)]
+pub(super) struct Activity {
+    state: Mutex<ActivityState>,
+}
+
+#[derive(Default)]
+struct ActivityState {
+    /// The id of the task being run and when it was started.
+    task: Option<(usize, Instant)>,
+    /// Whether the task has already been warned about.
+    warned: bool,
+    /// Whether the worker is blocked waiting on another task.
+    blocked: bool,
+}
+
+thread_local! {
+    /// The activity of the worker running on this thread, if its pool has a
+    /// watchdog.
+    static ACTIVITY: RefCell<Option<Arc<Activity>>> = const { RefCell::new(None) };
+}
+
+/// Marks the current thread as a worker the watchdog keeps an eye on.
+pub(super) fn enter(activity: Arc<Activity>) {
+    ACTIVITY.with(|a| a.replace(Some(activity)));
+}
+
+fn with_activity(f: impl FnOnce(&mut ActivityState)) {
+    ACTIVITY.with(|a| {
+        if let Some(activity) = a.borrow().as_ref() {
+            if let Ok(mut state) = activity.state.lock() {
+                f(&mut state);
+            }
+        }
+    });
+}
+
+/// Notes that the current worker started running task `id`. This does
+/// nothing if the pool has no watchdog.
+pub(super) fn task_started(id: usize) {
+    with_activity(|state| {
+        state.task = Some((id, Instant::now()));
+        state.warned = false;
+    });
+}
+
+/// Notes that the current worker is done with its task.
+pub(super) fn task_finished() {
+    with_activity(|state| state.task = None);
+}
+
+/// Marks the current worker as blocked until the returned guard is dropped.
+pub(super) fn blocked() -> BlockedGuard {
+    with_activity(|state| state.blocked = true);
+    return BlockedGuard;
+}
+
+pub(super) struct BlockedGuard;
+
+impl Drop for BlockedGuard {
+    fn drop(&mut self) {
+        with_activity(|state| state.blocked = false);
+    }
+}
+
+/// Keeps an eye on the workers of one run of a pool. It stops on its own
+/// once the pool is shut down and all of its workers are gone.
+pub(super) struct Watchdog {
+    pool: Cow<'static, str>,
+    threshold: Duration,
+    queue: Arc<TaskQueue>,
+    workers: Mutex<Vec<(usize, Weak<Activity>)>>,
+    /// How many slow tasks have been warned about.
+    slow: AtomicUsize,
+    /// How many times the pool has been reported as deadlocked.
+    deadlocks: AtomicUsize,
+}
+
+impl Watchdog {
+    /// Starts a watchdog thread that warns about tasks running for longer
+    /// than `threshold`.
+    pub(super) fn start(
+        pool: Cow<'static, str>,
+        threshold: Duration,
+        queue: Arc<TaskQueue>,
+    ) -> Arc<Watchdog> {
+        let watchdog = Arc::new(Watchdog {
+            pool,
+            threshold,
+            queue,
+            workers: Mutex::new(vec![]),
+            slow: AtomicUsize::new(0),
+            deadlocks: AtomicUsize::new(0),
+        });
+        let w = watchdog.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("{}-watchdog", watchdog.pool))
+            .spawn(move || w.run());
+        if let Err(e) = spawned {
+            serror!(
+                VANESSA_LOGGER,
+                "Failed to start the watchdog of pool '{}': {e}",
+                watchdog.pool
+            );
+        }
+        return watchdog;
+    }
+
+    /// Starts watching a new worker. The watchdog forgets about it once the
+    /// worker drops its activity.
+    pub(super) fn watch(&self, id: usize) -> Arc<Activity> {
+        let activity = Arc::new(Activity::default());
+        if let Ok(mut workers) = self.workers.lock() {
+            workers.push((id, Arc::downgrade(&activity)));
+        }
+        return activity;
+    }
+
+    /// Returns how many slow tasks have been warned about and how many
+    /// times the pool has been reported as deadlocked.
+    #[cfg(test)]
+    pub(super) fn reports(&self) -> (usize, usize) {
+        return (
+            self.slow.load(Ordering::Relaxed),
+            self.deadlocks.load(Ordering::Relaxed),
+        );
+    }
+
+    fn run(&self) {
+        sdebug!(
+            VANESSA_LOGGER,
+            "Starting the watchdog of pool '{}'.",
+            self.pool
+        );
+        let interval = (self.threshold / 4).clamp(Duration::from_millis(5), Duration::from_secs(1));
+        // whether the pool looked deadlocked last time, and whether that has
+        // been reported yet.
+        let mut suspicious = false;
+        let mut reported = false;
+        loop {
+            std::thread::sleep(interval);
+            let mut workers = match self.workers.lock() {
+                Ok(workers) => workers,
+                Err(_) => return,
+            };
+            workers.retain(|(_, activity)| activity.strong_count() > 0);
+            if workers.is_empty() && self.queue.is_closed() {
+                break;
+            }
+
+            let mut blocked = vec![];
+            for (worker, activity) in workers.iter() {
+                let activity = match activity.upgrade() {
+                    Some(activity) => activity,
+                    None => continue,
+                };
+                let mut state = match activity.state.lock() {
+                    Ok(state) => state,
+                    Err(_) => continue,
+                };
+                if let Some((task, started)) = state.task {
+                    let elapsed = started.elapsed();
+                    if elapsed >= self.threshold && !state.warned {
+                        state.warned = true;
+                        self.slow.fetch_add(1, Ordering::Relaxed);
+                        swarn!(
+                            VANESSA_LOGGER,
+                            "Task #{task} has been running on worker #{worker} of pool '{}' for {elapsed:?}.",
+                            self.pool
+                        );
+                    }
+                    if state.blocked {
+                        blocked.push(task);
+                    }
+                }
+            }
+
+            // every worker waiting on a task while tasks are still queued
+            // means nobody is left to run them. It has to look like that
+            // twice in a row, since a worker might just be about to wake up.
+            let deadlocked =
+                !workers.is_empty() && blocked.len() == workers.len() && self.queue.len() > 0;
+            if deadlocked && suspicious && !reported {
+                reported = true;
+                self.deadlocks.fetch_add(1, Ordering::Relaxed);
+                serror!(
+                    VANESSA_LOGGER,
+                    "Pool '{}' is deadlocked: every worker is blocked waiting on another task while {} tasks are queued. Blocked tasks: {blocked:?}",
+                    self.pool,
+                    self.queue.len()
+                );
+            }
+            if !deadlocked {
+                reported = false;
+            }
+            suspicious = deadlocked;
+        }
+        sdebug!(
+            VANESSA_LOGGER,
+            "Stopping the watchdog of pool '{}'.",
+            self.pool
+        );
+    }
+}